@@ -0,0 +1,632 @@
+use std::fmt;
+use std::str::FromStr;
+
+use indoc::formatdoc;
+
+use thousands::{Separable, SeparatorPolicy, digits};
+
+use chrono::{DateTime, Utc};
+
+use crate::config::{DatabaseConfig, PoolConfig};
+use crate::database::{Blocks, Database};
+use crate::epoch::format_duration;
+use crate::error::BotError;
+use crate::monitor::{snapshot_key, Monitor};
+use crate::monitors::{MissedBlocks, MissedSnapshot};
+use crate::ratelimit::ResponseCache;
+use crate::schedule::ScheduleStore;
+use crate::state::StateStore;
+
+/// Shortest prefix `!delegator` looks up, enough to rule out most clashes.
+const MIN_ADDRESS_PREFIX: usize = 10;
+/// Addresses listed when a prefix is ambiguous.
+const MAX_ADDRESS_MATCHES: i64 = 5;
+/// Epochs of rewards `!delegator` shows.
+const REWARD_EPOCHS: i64 = 5;
+/// Epochs in the `!blocks` history table.
+const BLOCK_EPOCHS: i64 = 6;
+/// Upcoming slots `!schedule` lists per pool.
+const SCHEDULE_SLOTS: usize = 10;
+
+/// A parsed chat command with its typed arguments.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help { command: Option<String> },
+    Party,
+    Boo,
+    Status { ticker: Option<String> },
+    Delegator { address: String },
+    Epoch,
+    Blocks { epoch: Option<i64> },
+    Schedule,
+    Whoami,
+}
+
+/// Who may run a command. Each role may also run everything the roles before
+/// it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Public,
+    Operator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Public => write!(f, "public"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// The role of `user_id`: the `admins`/`operators` lists take precedence,
+/// otherwise it follows from the sender's power level in the room.
+pub fn role_for(config: &DatabaseConfig, user_id: &str, power_level: i64) -> Role {
+    if config.admins.iter().any(|admin| admin == user_id) {
+        Role::Admin
+    } else if config.operators.iter().any(|operator| operator == user_id) {
+        Role::Operator
+    } else if config.adminpowerlevel.is_some_and(|level| power_level >= level) {
+        Role::Admin
+    } else if config.operatorpowerlevel.is_some_and(|level| power_level >= level) {
+        Role::Operator
+    } else {
+        Role::Public
+    }
+}
+
+/// Registry entry describing one chat command.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// The least role allowed to run the command.
+    pub role: Role,
+    /// Whether the reply only reads data, so a recent one may be reused.
+    pub cached: bool,
+    parse: fn(&[&str]) -> Result<Command, String>,
+}
+
+/// Every command the bot answers, in the order `help` lists them.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        role: Role::Public,
+        cached: false,
+        usage: "[command]",
+        help: "List the commands, or describe one",
+        parse: |args| Ok(Command::Help { command: optional(args, 0, "command")? }),
+    },
+    CommandSpec {
+        name: "status",
+        role: Role::Public,
+        cached: true,
+        usage: "[ticker]",
+        help: "Live stake, saturation and delegator count",
+        parse: |args| Ok(Command::Status { ticker: optional(args, 0, "ticker")? }),
+    },
+    CommandSpec {
+        name: "delegator",
+        role: Role::Public,
+        cached: true,
+        usage: "<stake address or prefix>",
+        help: "Stake, pool and recent rewards of a stake address",
+        parse: |args| Ok(Command::Delegator { address: stake_address(args)? }),
+    },
+    CommandSpec {
+        name: "epoch",
+        role: Role::Public,
+        cached: false,
+        usage: "",
+        help: "Current epoch, its progress and when the next one starts",
+        parse: |_| Ok(Command::Epoch),
+    },
+    CommandSpec {
+        name: "blocks",
+        role: Role::Public,
+        cached: true,
+        usage: "[epoch]",
+        help: "Forged and assigned blocks for an epoch and the last few",
+        parse: |args| Ok(Command::Blocks { epoch: optional(args, 0, "epoch")? }),
+    },
+    CommandSpec {
+        name: "schedule",
+        role: Role::Public,
+        cached: false,
+        usage: "",
+        help: "Upcoming leader slots from the imported schedule",
+        parse: |_| Ok(Command::Schedule),
+    },
+    CommandSpec {
+        name: "whoami",
+        role: Role::Public,
+        cached: false,
+        usage: "",
+        help: "Show your role for bot commands",
+        parse: |_| Ok(Command::Whoami),
+    },
+    CommandSpec {
+        name: "party",
+        role: Role::Public,
+        cached: false,
+        usage: "",
+        help: "🎉",
+        parse: |_| Ok(Command::Party),
+    },
+    CommandSpec {
+        name: "boo",
+        role: Role::Public,
+        cached: false,
+        usage: "",
+        help: "👻",
+        parse: |_| Ok(Command::Boo),
+    },
+];
+
+/// Outcome of reading a message as a command.
+pub enum Parsed {
+    /// The message doesn't start with the command prefix.
+    NotCommand,
+    Command(&'static CommandSpec, Command),
+    /// The message is a command the bot can't run; the text says why.
+    Invalid(String),
+}
+
+/// What a command needs to run.
+pub struct CommandContext<'a> {
+    pub config: &'a DatabaseConfig,
+    pub db: &'a Database,
+    pub schedules: &'a ScheduleStore,
+    pub state: &'a StateStore,
+    pub cache: &'a ResponseCache,
+    pub room_id: &'a str,
+    pub role: Role,
+}
+
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+/// Parses `body` as `<prefix><name> [args...]`. The name must match a
+/// registered command exactly; text that merely mentions a command is not one.
+pub fn parse(prefix: &str, body: &str) -> Parsed {
+
+    let Some(rest) = body.trim().strip_prefix(prefix) else { return Parsed::NotCommand };
+
+    let mut words = rest.split_whitespace();
+    let Some(name) = words.next() else { return Parsed::NotCommand };
+    let args: Vec<&str> = words.collect();
+
+    let Some(spec) = find(name) else {
+        return Parsed::Invalid(format!("Unknown command {}{}. Try {}help", prefix, name, prefix));
+    };
+
+    match (spec.parse)(&args) {
+        Ok(command) => Parsed::Command(spec, command),
+        Err(e) => Parsed::Invalid(format!("{}\nUsage: {}", e, spec.usage_line(prefix))),
+    }
+}
+
+impl CommandSpec {
+
+    pub fn usage_line(&self, prefix: &str) -> String {
+        format!("{}{} {}", prefix, self.name, self.usage).trim_end().to_owned()
+    }
+
+}
+
+/// One line per command `role` may run, for `help` and the greeting in new
+/// rooms.
+pub fn summary(prefix: &str, role: Role) -> String {
+    COMMANDS.iter()
+        .filter(|spec| spec.role <= role)
+        .map(|spec| format!("    ▫️  {}    {}", spec.usage_line(prefix), spec.help))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Reads the argument at `index` if present, and rejects any after the last
+/// one the command takes.
+fn optional<T: FromStr>(args: &[&str], index: usize, name: &str) -> Result<Option<T>, String> {
+
+    if args.len() > index + 1 {
+        return Err(format!("Too many arguments after {}", name));
+    }
+
+    args.get(index)
+        .map(|value| value.parse().map_err(|_| format!("Invalid {} `{}`", name, value)))
+        .transpose()
+}
+
+/// Reads a stake address, or the start of one, as the only argument.
+fn stake_address(args: &[&str]) -> Result<String, String> {
+
+    let address = match args {
+        [] => return Err("Missing stake address".to_owned()),
+        [address] => address.to_lowercase(),
+        _ => return Err("Too many arguments after stake address".to_owned()),
+    };
+
+    // Test networks use `stake_test`, the only place an underscore may be.
+    let rest = address.strip_prefix("stake_test").or_else(|| address.strip_prefix("stake"));
+    let valid = rest.is_some_and(|rest| rest.chars().all(|c| c.is_ascii_alphanumeric()));
+
+    if !valid || address.len() < MIN_ADDRESS_PREFIX {
+        return Err(format!("Invalid stake address `{}`, give at least its first {} characters", address, MIN_ADDRESS_PREFIX));
+    }
+
+    Ok(address)
+}
+
+/// Runs `command` if the sender's role allows it and returns the reply to
+/// post. Replies to cached commands are reused per room until they expire.
+pub async fn execute(spec: &CommandSpec, command: Command, ctx: &CommandContext<'_>) -> Result<String, BotError> {
+
+    if ctx.role < spec.role {
+        return Ok(format!("⛔   {}{} needs the {} role", ctx.config.commandprefix, spec.name, spec.role));
+    }
+
+    if !spec.cached {
+        return run(command, ctx).await;
+    }
+
+    let key = format!("{}|{:?}", ctx.room_id, command);
+
+    if let Some(reply) = ctx.cache.get(&key) {
+        return Ok(reply);
+    }
+
+    let reply = run(command, ctx).await?;
+    ctx.cache.put(key, reply.clone());
+
+    Ok(reply)
+}
+
+async fn run(command: Command, ctx: &CommandContext<'_>) -> Result<String, BotError> {
+
+    let prefix = &ctx.config.commandprefix;
+
+    match command {
+        Command::Help { command: None } => Ok(format!("🤖   Commands\n{}", summary(prefix, ctx.role))),
+        Command::Help { command: Some(name) } => {
+            let name = name.trim_start_matches(prefix.as_str());
+
+            Ok(match find(name) {
+                Some(spec) => format!("{}\n    {}", spec.usage_line(prefix), spec.help),
+                None => format!("Unknown command {}{}. Try {}help", prefix, name, prefix),
+            })
+        }
+        Command::Party => Ok("🎉🎊🥳 let's PARTY!! 🥳🎊🎉".to_owned()),
+        Command::Boo => Ok("👻  Booooo!!  👻".to_owned()),
+        Command::Status { ticker } => status(ctx, ticker.as_deref()).await,
+        Command::Delegator { address } => delegator(ctx, &address).await,
+        Command::Epoch => Ok(epoch(ctx)),
+        Command::Blocks { epoch } => blocks(ctx, epoch).await,
+        Command::Schedule => Ok(schedule(ctx)),
+        Command::Whoami => Ok(format!("Your role here is {}", ctx.role)),
+    }
+}
+
+/// The pools alerting in the command's room, or every pool if none do.
+fn room_pools<'a>(ctx: &CommandContext<'a>) -> Vec<&'a PoolConfig> {
+    let roompools: Vec<&PoolConfig> = ctx.config.pools.iter().filter(|pool| pool.matrixroom == ctx.room_id).collect();
+    if roompools.is_empty() { ctx.config.pools.iter().collect() } else { roompools }
+}
+
+/// Statistics for the named pool, or else the pools alerting in this room,
+/// or else every pool.
+async fn status(ctx: &CommandContext<'_>, ticker: Option<&str>) -> Result<String, BotError> {
+
+    let pools: Vec<&PoolConfig> = match ticker {
+        Some(ticker) => {
+            let found: Vec<&PoolConfig> = ctx.config.pools.iter().filter(|pool| pool.ticker.eq_ignore_ascii_case(ticker)).collect();
+            if found.is_empty() {
+                return Ok(format!("No pool with ticker {} is monitored", ticker));
+            }
+            found
+        }
+        None => room_pools(ctx),
+    };
+
+    let mut replies = vec![];
+
+    for pool in pools {
+
+        let poolstats = ctx.db.pool_stats(&pool.poolid).await?;
+        let stats = poolstats.first().ok_or(BotError::NoRows("balance.bot_pool_stats"))?;
+
+        let policy = SeparatorPolicy {
+            separator: ',',
+            groups:    &[3],
+            digits:    digits::ASCII_DECIMAL,
+        };
+
+        let live_stake = stats.live_stake.separate_by_policy(policy);
+        let live_saturation = &stats.live_saturation.separate_by_policy(policy);
+        let live_delegator_count = &stats.live_delegator_count;
+
+        replies.push(formatdoc!(r#"
+        ⚖️    {} Pool Statistics   🧐
+            ▫️  Stake            {} ₳
+            ▫️  Saturation    {} %
+            ▫️  Delegates     {}"#, pool.ticker, live_stake, live_saturation, live_delegator_count));
+    }
+
+    Ok(replies.join("\n\n"))
+}
+
+/// Live stake, current pool and recent rewards of the stake address starting
+/// with `prefix`.
+async fn delegator(ctx: &CommandContext<'_>, prefix: &str) -> Result<String, BotError> {
+
+    let matches = ctx.db.stake_address_matches(prefix, MAX_ADDRESS_MATCHES).await?;
+
+    let stake_address = match matches.as_slice() {
+        [] => return Ok(format!("No stake address starts with {}", prefix)),
+        [delegator] => &delegator.addr_view,
+        _ => {
+            let listed: Vec<String> = matches.iter().map(|delegator| format!("    ▫️  {}", delegator.addr_view)).collect();
+            return Ok(format!("{} matches more than one stake address, add some characters:\n{}", prefix, listed.join("\n")));
+        }
+    };
+
+    let addresses = ctx.db.address_value(stake_address).await?;
+    let addressdata = addresses.first().ok_or(BotError::NoRows("balance.bot_address_value"))?;
+
+    let policy = SeparatorPolicy {
+        separator: ',',
+        groups:    &[3],
+        digits:    digits::ASCII_DECIMAL,
+    };
+
+    let ada = addressdata.ada_value.separate_by_policy(policy);
+
+    // Pool and epoch both come from the latest certificate so they agree.
+    let (pool, since) = match ctx.db.delegation(stake_address).await?.into_iter().next() {
+        Some(delegation) => (delegation.pool_view, format!("Epoch {}", delegation.active_epoch_no)),
+        None => ("Not delegated".to_owned(), "-".to_owned()),
+    };
+
+    let rewards: Vec<String> = ctx.db.reward_history(stake_address, REWARD_EPOCHS).await?.iter()
+        .map(|reward| format!("        Epoch {}   {} ₳", reward.epoch_no, reward.rewards.round_dp(2).separate_by_policy(policy)))
+        .collect();
+    let rewards = if rewards.is_empty() { "        None yet".to_owned() } else { rewards.join("\n") };
+
+    Ok(formatdoc!(r#"
+    🔎   {}   {} ₳
+        ▫️  Pool  {}
+        ▫️  Since  {}
+        ▫️  Rewards
+    {}"#, addressdata.stake_address, ada, pool, since, rewards))
+}
+
+/// Where the chain is in the current epoch, from the configured genesis.
+fn epoch(ctx: &CommandContext<'_>) -> String {
+
+    let now = ctx.config.genesis.epoch_at(Utc::now());
+
+    let policy = SeparatorPolicy {
+        separator: ',',
+        groups:    &[3],
+        digits:    digits::ASCII_DECIMAL,
+    };
+
+    formatdoc!(r#"
+    🕰️    Epoch {}   {:.1} %
+        ▫️  Slot           {} / {}
+        ▫️  Chain slot  {}
+        ▫️  Remaining  {}
+        ▫️  Next epoch  {}"#,
+        now.epoch, now.progress(),
+        now.epochslot.separate_by_policy(policy), now.epochlength.separate_by_policy(policy),
+        now.slot.separate_by_policy(policy),
+        format_duration(now.remaining),
+        now.nextepoch.format("%Y-%m-%d %H:%M UTC"))
+}
+
+/// Block counts of the room's pools for `epoch`, the current one unless
+/// given, followed by the last few epochs.
+async fn blocks(ctx: &CommandContext<'_>, epoch: Option<i64>) -> Result<String, BotError> {
+
+    let current = ctx.config.genesis.epoch_at(Utc::now()).epoch as i64;
+    let epoch = epoch.unwrap_or(current);
+
+    if epoch > current {
+        return Ok(format!("Epoch {} hasn't started yet, this is epoch {}", epoch, current));
+    }
+
+    let oldest = epoch.min(current - BLOCK_EPOCHS + 1);
+    let mut replies = vec![];
+
+    for pool in room_pools(ctx) {
+
+        let history = ctx.db.block_history(&pool.poolid, oldest).await?;
+        let lifetime = ctx.db.lifetime_blocks(&pool.poolid).await?;
+        let lifetime = lifetime.first().ok_or(BotError::NoRows("block"))?.lifetime_blocks;
+
+        // Epochs without a block have no row, the assigned count is only
+        // known for epochs whose schedule was imported, and missed slots
+        // only for epochs the bot checked.
+        let missed: Option<MissedSnapshot> = ctx.state.snapshot(&snapshot_key(&pool.ticker, MissedBlocks::NAME))?;
+        let outcomes = missed.unwrap_or_default().outcomes;
+
+        let blocks_in = |epoch_no: i64| {
+            let mut blocks = history.iter()
+                .find(|blocks| blocks.epoch_no == epoch_no)
+                .cloned()
+                .unwrap_or(Blocks { epoch_no, blocks_forged: 0, assigned: None, missed: None, orphaned: None });
+
+            blocks.assigned = ctx.schedules.assigned(&pool.ticker, epoch_no);
+            if let Some(outcome) = outcomes.get(&epoch_no) {
+                blocks.missed = Some(outcome.missed);
+                blocks.orphaned = Some(outcome.orphaned);
+            }
+            blocks
+        };
+
+        let shown = blocks_in(epoch);
+
+        let table: Vec<String> = (current - BLOCK_EPOCHS + 1..=current).rev()
+            .map(|epoch_no| {
+                let blocks = blocks_in(epoch_no);
+                format!("        {}    {} / {}", epoch_no, blocks.blocks_forged, count(blocks.assigned))
+            })
+            .collect();
+
+        let policy = SeparatorPolicy {
+            separator: ',',
+            groups:    &[3],
+            digits:    digits::ASCII_DECIMAL,
+        };
+
+        replies.push(formatdoc!(r#"
+        ⚒️   {} Blocks   Epoch {}
+            ▫️  Forged       {} / {}
+            ▫️  Missed       {}
+            ▫️  Orphaned   {}
+            ▫️  Lifetime     {}
+            ▫️  Recent epochs
+        {}"#,
+            pool.ticker, shown.epoch_no,
+            shown.blocks_forged, count(shown.assigned),
+            count(shown.missed),
+            count(shown.orphaned),
+            lifetime.separate_by_policy(policy),
+            table.join("\n")));
+    }
+
+    Ok(replies.join("\n\n"))
+}
+
+/// The next leader slots of the room's pools.
+fn schedule(ctx: &CommandContext<'_>) -> String {
+
+    let now = Utc::now();
+
+    let policy = SeparatorPolicy {
+        separator: ',',
+        groups:    &[3],
+        digits:    digits::ASCII_DECIMAL,
+    };
+
+    let mut replies = vec![];
+
+    for pool in room_pools(ctx) {
+
+        let slots: Vec<String> = ctx.schedules.slots_between(&pool.ticker, now, DateTime::<Utc>::MAX_UTC).iter()
+            .take(SCHEDULE_SLOTS)
+            .map(|slot| format!("    ▫️  Slot {}   {}   in {}",
+                slot.slot.separate_by_policy(policy), slot.at.format("%Y-%m-%d %H:%M UTC"), format_duration(slot.at - now)))
+            .collect();
+        let slots = if slots.is_empty() { "    ▫️  None scheduled".to_owned() } else { slots.join("\n") };
+
+        replies.push(format!("📅   {} Upcoming Blocks\n{}", pool.ticker, slots));
+    }
+
+    replies.join("\n\n")
+}
+
+/// A block count, or `-` when it isn't known.
+fn count(value: Option<i64>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stake_address_accepts_mainnet_and_test_networks() {
+        assert_eq!(stake_address(&["Stake1UXYZ123"]), Ok("stake1uxyz123".to_owned()));
+        assert_eq!(stake_address(&["stake_test1uq"]), Ok("stake_test1uq".to_owned()));
+    }
+
+    #[test]
+    fn stake_address_rejects_other_text() {
+        assert!(stake_address(&[]).is_err());
+        assert!(stake_address(&["stake1"]).is_err());
+        assert!(stake_address(&["stake_1uxyz123"]).is_err());
+        assert!(stake_address(&["addr1qxyz1234"]).is_err());
+        assert!(stake_address(&["stake1uxyz123", "extra"]).is_err());
+    }
+
+    #[test]
+    fn parse_reads_registered_commands_only() {
+        assert!(matches!(parse("!", "hello there"), Parsed::NotCommand));
+        assert!(matches!(parse("!", "!"), Parsed::NotCommand));
+        assert!(matches!(parse("!", "  !epoch  "), Parsed::Command(spec, Command::Epoch) if spec.name == "epoch"));
+        assert!(matches!(parse("!", "!blocks 480"), Parsed::Command(_, Command::Blocks { epoch: Some(480) })));
+        assert!(matches!(parse("!", "!status"), Parsed::Command(_, Command::Status { ticker: None })));
+        assert!(matches!(parse("?", "?help blocks"), Parsed::Command(_, Command::Help { command: Some(name) }) if name == "blocks"));
+        assert!(matches!(parse("!", "!epochs"), Parsed::Invalid(text) if text == "Unknown command !epochs. Try !help"));
+    }
+
+    #[test]
+    fn parse_explains_bad_arguments() {
+        let Parsed::Invalid(text) = parse("!", "!blocks last") else { panic!("parsed a bad epoch") };
+        assert_eq!(text, "Invalid epoch `last`\nUsage: !blocks [epoch]");
+
+        let Parsed::Invalid(text) = parse("!", "!status BALNC OTHER") else { panic!("parsed extra arguments") };
+        assert_eq!(text, "Too many arguments after ticker\nUsage: !status [ticker]");
+    }
+
+    #[test]
+    fn role_follows_lists_then_power_level() {
+        let config = DatabaseConfig {
+            admins: vec!["@admin:example.org".to_owned()],
+            operators: vec!["@operator:example.org".to_owned()],
+            adminpowerlevel: Some(100),
+            operatorpowerlevel: Some(50),
+            ..DatabaseConfig::default()
+        };
+
+        assert_eq!(role_for(&config, "@admin:example.org", 0), Role::Admin);
+        assert_eq!(role_for(&config, "@operator:example.org", 0), Role::Operator);
+        assert_eq!(role_for(&config, "@mod:example.org", 100), Role::Admin);
+        assert_eq!(role_for(&config, "@mod:example.org", 50), Role::Operator);
+        assert_eq!(role_for(&config, "@user:example.org", 0), Role::Public);
+        assert_eq!(role_for(&DatabaseConfig::default(), "@user:example.org", 0), Role::Public);
+    }
+
+    #[tokio::test]
+    async fn execute_refuses_roles_below_the_command() {
+        let config = DatabaseConfig { commandprefix: "!".to_owned(), ..DatabaseConfig::default() };
+        let missing = std::env::temp_dir().join("balance_bot_commands_test_missing");
+        let missing = missing.to_str().unwrap();
+
+        let db = Database::new(&config).unwrap();
+        let schedules = ScheduleStore::open(missing).unwrap();
+        let state = StateStore::open(missing).unwrap();
+        let cache = ResponseCache::new(std::time::Duration::ZERO);
+
+        let spec = CommandSpec {
+            name: "shutdown",
+            role: Role::Operator,
+            cached: false,
+            usage: "",
+            help: "",
+            parse: |_| Ok(Command::Boo),
+        };
+
+        let cases = [
+            (Role::Public, "⛔   !shutdown needs the operator role"),
+            (Role::Operator, "👻  Booooo!!  👻"),
+            (Role::Admin, "👻  Booooo!!  👻"),
+        ];
+
+        for (role, reply) in cases {
+            let ctx = CommandContext { config: &config, db: &db, schedules: &schedules, state: &state, cache: &cache, room_id: "!room", role };
+            assert_eq!(execute(&spec, Command::Boo, &ctx).await.unwrap(), reply, "{}", role);
+        }
+    }
+
+    #[test]
+    fn optional_reads_one_argument() {
+        assert_eq!(optional::<i64>(&[], 0, "epoch"), Ok(None));
+        assert_eq!(optional::<i64>(&["480"], 0, "epoch"), Ok(Some(480)));
+        assert_eq!(optional::<i64>(&["-"], 0, "epoch"), Err("Invalid epoch `-`".to_owned()));
+        assert_eq!(optional::<i64>(&["480", "481"], 0, "epoch"), Err("Too many arguments after epoch".to_owned()));
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+
+use log::warn;
+
+use reqwest::Url;
+
+use rust_decimal::Decimal;
+
+use chrono::{DateTime, Utc};
+
+use crate::epoch::Genesis;
+use crate::event;
+use crate::monitors;
+
+const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+const CONFIG_PATH_ENV: &str = "BALANCE_BOT_CONFIG";
+const DEFAULT_STATE_FILE: &str = "balance_bot_state.json";
+const DEFAULT_SCHEDULE_FILE: &str = "balance_bot_schedule.json";
+const DEFAULT_SESSION_FILE: &str = "balance_bot_session.json";
+const DEFAULT_STORE_PATH: &str = "balance_bot_store";
+const DEFAULT_DB_POOL_SIZE: usize = 4;
+const DEFAULT_DB_TIMEOUT: u64 = 30;
+const DEFAULT_NETWORK: &str = "mainnet";
+const DEFAULT_TELEGRAM_API: &str = "https://api.telegram.org";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub hostaddr: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub dbname: String,
+    /// Maximum number of pooled database connections.
+    pub dbpoolsize: usize,
+    /// Seconds to keep retrying a database connection before giving up.
+    pub dbtimeout: u64,
+    /// Room told when the database goes down and comes back, and when a
+    /// monitor crashes, if set.
+    pub dbalertroom: String,
+    pub matrixuser: String,
+    pub matrixpassword: String,
+    pub matrixroom: String,
+    pub matrixhomeserver: String,
+    /// Where the Matrix login is kept so restarts reuse the same device.
+    pub matrixsessionfile: String,
+    /// Directory of the Matrix state and end-to-end encryption stores.
+    pub matrixstore: String,
+    /// Optional passphrase the stores are encrypted with.
+    pub matrixstorepassphrase: String,
+    /// Users (`@user:server`) or servers (`server`) whose room invites are
+    /// accepted. Invites from anyone else are declined.
+    pub inviteallow: Vec<String>,
+    /// Text that starts a chat command, `!` unless configured.
+    pub commandprefix: String,
+    /// User ids allowed to run every command.
+    pub admins: Vec<String>,
+    /// User ids allowed to run operator commands.
+    pub operators: Vec<String>,
+    /// Room power level that grants the admin role, 100 unless configured.
+    pub adminpowerlevel: Option<i64>,
+    /// Room power level that grants the operator role, 50 unless configured.
+    pub operatorpowerlevel: Option<i64>,
+    /// Commands one sender may send in a burst, 3 unless configured.
+    pub usercommandburst: u32,
+    /// Commands a minute one sender may send once the burst is used up.
+    pub usercommandrate: u32,
+    /// Commands a room may send in a burst, 10 unless configured.
+    pub roomcommandburst: u32,
+    /// Commands a minute a room may send once the burst is used up.
+    pub roomcommandrate: u32,
+    /// Seconds replies to read-only commands are reused, 60 unless
+    /// configured. Zero turns the cache off.
+    pub commandcachettl: Option<u64>,
+    pub ticker: String,
+    pub poolid: String,
+    pub pools: Vec<PoolConfig>,
+    /// Where the monitor snapshots are kept between restarts.
+    pub statefile: String,
+    /// Where imported leader schedules are kept.
+    pub schedulefile: String,
+    /// Per-monitor settings keyed by monitor name. Monitors left out run
+    /// with the defaults.
+    pub monitors: HashMap<String, MonitorConfig>,
+    /// Minutes before a scheduled block to remind the pool's rooms of it.
+    /// Zero or unset sends no reminders.
+    pub reminderminutes: u64,
+    /// Event kinds never sent, such as `stake_changed`.
+    pub mutedevents: Vec<String>,
+    /// Seconds to collect events before sending them, so a burst reaches a
+    /// room as one message per pool. Zero or unset sends each on its own.
+    pub notifybatch: u64,
+    /// Where pool events are sent besides Matrix.
+    pub notifiers: Vec<NotifierConfig>,
+    /// Cardano network the pools run on: `mainnet` unless configured,
+    /// `preprod`, `preview`, or any name when the genesis keys below are set.
+    pub network: String,
+    /// Shelley genesis overrides for networks the bot doesn't know.
+    pub shelleystart: Option<DateTime<Utc>>,
+    pub shelleyepoch: Option<u64>,
+    pub shelleyslot: Option<u64>,
+    pub epochlength: Option<u64>,
+    pub slotlength: Option<u64>,
+    /// Epoch timing of `network` with the overrides applied.
+    #[serde(skip)]
+    pub genesis: Genesis,
+}
+
+/// A stake pool watched by the bot. `matrixroom` falls back to the top-level
+/// value when a pool leaves it out.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub ticker: String,
+    pub poolid: String,
+    pub matrixroom: String,
+    /// Minimum live stake movement, in ADA, before a stake alert is sent.
+    pub stakebuffer: Option<Decimal>,
+}
+
+/// A place pool events are sent to. `service` picks the backend and the
+/// keys it needs: `webhook` for `discord` and `slack`, `bottoken` and `chatid`
+/// for `telegram`. A `matrix` entry needs none, and replaces the default of
+/// sending every event to the pool rooms.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct NotifierConfig {
+    pub service: String,
+    pub webhook: String,
+    pub bottoken: String,
+    pub chatid: String,
+    /// Bot API base URL, `https://api.telegram.org` unless configured.
+    pub apiurl: String,
+    /// Tickers of the pools whose events are sent. Empty sends every pool's.
+    pub pools: Vec<String>,
+    /// Event kinds sent. Empty sends every kind.
+    pub events: Vec<String>,
+}
+
+/// How often a monitor runs and for how long.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MonitorConfig {
+    pub enabled: bool,
+    /// Seconds between runs.
+    pub interval: u64,
+    /// Up to this many seconds are added to each interval at random.
+    pub jitter: u64,
+    /// Seconds a run may take before it is abandoned.
+    pub timeout: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self { enabled: true, interval: 60, jitter: 0, timeout: 50 }
+    }
+}
+
+impl PoolConfig {
+
+    pub fn stake_buffer(&self) -> Decimal {
+        self.stakebuffer.unwrap_or_else(|| Decimal::new(100_000, 0))
+    }
+
+}
+
+impl DatabaseConfig {
+
+    /// Loads the bot configuration.
+    ///
+    /// The YAML file is taken from `--config <path>`, then `BALANCE_BOT_CONFIG`,
+    /// then `./config.yaml`. Only an explicitly requested file has to exist, so a
+    /// deployment can still be configured purely through the environment. Any
+    /// key can be overridden by its environment variable (`HOST_ADDRESS`,
+    /// `MATRIX_ROOM`, `POOL_ID`, ...) and the result is validated before
+    /// it is returned.
+    ///
+    /// Without a `pools` list a single pool is built from the top-level
+    /// `ticker`, `poolid` and `matrixroom` keys.
+    pub fn load() -> anyhow::Result<Self> {
+
+        let (path, explicit) = match Self::config_path()? {
+            Some(path) => (path, true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let mut config = if explicit || path.exists() {
+            Self::from_file(&path)?
+        } else {
+            Self::default()
+        };
+
+        config.apply_env()?;
+        config.resolve_pools();
+
+        if config.network.is_empty() {
+            config.network = DEFAULT_NETWORK.to_owned();
+        }
+        config.resolve_genesis();
+
+        if config.statefile.is_empty() {
+            config.statefile = DEFAULT_STATE_FILE.to_owned();
+        }
+        if config.schedulefile.is_empty() {
+            config.schedulefile = DEFAULT_SCHEDULE_FILE.to_owned();
+        }
+        if config.matrixsessionfile.is_empty() {
+            config.matrixsessionfile = DEFAULT_SESSION_FILE.to_owned();
+        }
+        if config.matrixstore.is_empty() {
+            config.matrixstore = DEFAULT_STORE_PATH.to_owned();
+        }
+        if config.commandprefix.is_empty() {
+            config.commandprefix = "!".to_owned();
+        }
+        config.adminpowerlevel.get_or_insert(100);
+        config.operatorpowerlevel.get_or_insert(50);
+        if config.usercommandburst == 0 {
+            config.usercommandburst = 3;
+        }
+        if config.usercommandrate == 0 {
+            config.usercommandrate = 6;
+        }
+        if config.roomcommandburst == 0 {
+            config.roomcommandburst = 10;
+        }
+        if config.roomcommandrate == 0 {
+            config.roomcommandrate = 20;
+        }
+        config.commandcachettl.get_or_insert(60);
+        if config.dbpoolsize == 0 {
+            config.dbpoolsize = DEFAULT_DB_POOL_SIZE;
+        }
+        if config.dbtimeout == 0 {
+            config.dbtimeout = DEFAULT_DB_TIMEOUT;
+        }
+        for notifier in config.notifiers.iter_mut() {
+            if notifier.service == "telegram" && notifier.apiurl.is_empty() {
+                notifier.apiurl = DEFAULT_TELEGRAM_API.to_owned();
+            }
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn config_path() -> anyhow::Result<Option<PathBuf>> {
+
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if arg == "--config" || arg == "-c" {
+                let path = args.next().ok_or_else(|| anyhow!("{} requires a file path", arg))?;
+                return Ok(Some(PathBuf::from(path)));
+            }
+            if let Some(path) = arg.strip_prefix("--config=") {
+                return Ok(Some(PathBuf::from(path)));
+            }
+        }
+
+        Ok(env::var(CONFIG_PATH_ENV).ok().map(PathBuf::from))
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file {}", path.display()))?;
+
+        serde_yaml::from_str(&contents)
+            .with_context(|| format!("Unable to parse config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+
+        override_value(&mut self.hostaddr, "HOST_ADDRESS")?;
+        override_value(&mut self.port, "DB_PORT")?;
+        override_value(&mut self.user, "DB_USER")?;
+        override_value(&mut self.password, "DB_PASSWORD")?;
+        deprecated_value(&mut self.port, "PORT", "DB_PORT")?;
+        deprecated_value(&mut self.user, "USER", "DB_USER")?;
+        deprecated_value(&mut self.password, "PASSWORD", "DB_PASSWORD")?;
+        override_value(&mut self.dbname, "DB_NAME")?;
+        override_value(&mut self.dbpoolsize, "DB_POOL_SIZE")?;
+        override_value(&mut self.dbtimeout, "DB_TIMEOUT")?;
+        override_value(&mut self.dbalertroom, "DB_ALERT_ROOM")?;
+        override_value(&mut self.matrixuser, "MATRIX_USER")?;
+        override_value(&mut self.matrixpassword, "MATRIX_PASSWORD")?;
+        override_value(&mut self.matrixroom, "MATRIX_ROOM")?;
+        override_value(&mut self.matrixhomeserver, "MATRIX_HOMESERVER")?;
+        override_value(&mut self.matrixsessionfile, "MATRIX_SESSION_FILE")?;
+        override_value(&mut self.matrixstore, "MATRIX_STORE")?;
+        override_value(&mut self.matrixstorepassphrase, "MATRIX_STORE_PASSPHRASE")?;
+        override_list(&mut self.inviteallow, "INVITE_ALLOW");
+        override_value(&mut self.commandprefix, "COMMAND_PREFIX")?;
+        override_list(&mut self.admins, "ADMINS");
+        override_list(&mut self.operators, "OPERATORS");
+        override_option(&mut self.adminpowerlevel, "ADMIN_POWER_LEVEL")?;
+        override_option(&mut self.operatorpowerlevel, "OPERATOR_POWER_LEVEL")?;
+        override_value(&mut self.usercommandburst, "USER_COMMAND_BURST")?;
+        override_value(&mut self.usercommandrate, "USER_COMMAND_RATE")?;
+        override_value(&mut self.roomcommandburst, "ROOM_COMMAND_BURST")?;
+        override_value(&mut self.roomcommandrate, "ROOM_COMMAND_RATE")?;
+        override_option(&mut self.commandcachettl, "COMMAND_CACHE_TTL")?;
+        override_value(&mut self.network, "NETWORK")?;
+        override_option(&mut self.shelleystart, "SHELLEY_START")?;
+        override_option(&mut self.shelleyepoch, "SHELLEY_EPOCH")?;
+        override_option(&mut self.shelleyslot, "SHELLEY_SLOT")?;
+        override_option(&mut self.epochlength, "EPOCH_LENGTH")?;
+        override_option(&mut self.slotlength, "SLOT_LENGTH")?;
+        override_value(&mut self.ticker, "POOL_TICKER")?;
+        override_value(&mut self.poolid, "POOL_ID")?;
+        override_value(&mut self.statefile, "STATE_FILE")?;
+        override_value(&mut self.schedulefile, "SCHEDULE_FILE")?;
+        override_value(&mut self.reminderminutes, "REMINDER_MINUTES")?;
+        override_list(&mut self.mutedevents, "MUTED_EVENTS");
+        override_value(&mut self.notifybatch, "NOTIFY_BATCH")?;
+
+        Ok(())
+    }
+
+    fn resolve_pools(&mut self) {
+
+        if self.pools.is_empty() {
+            self.pools.push(PoolConfig {
+                ticker: self.ticker.clone(),
+                poolid: self.poolid.clone(),
+                ..PoolConfig::default()
+            });
+        }
+
+        for pool in self.pools.iter_mut() {
+            if pool.matrixroom.is_empty() {
+                pool.matrixroom = self.matrixroom.clone();
+            }
+        }
+    }
+
+    /// Starts from the timing of a known network and applies any genesis
+    /// keys on top. An unknown network with keys missing is left with zero
+    /// lengths for `validate` to report.
+    fn resolve_genesis(&mut self) {
+
+        let mut genesis = Genesis::network(&self.network).unwrap_or_default();
+
+        if let Some(shelleystart) = self.shelleystart {
+            genesis.shelleystart = shelleystart;
+        }
+        if let Some(shelleyepoch) = self.shelleyepoch {
+            genesis.shelleyepoch = shelleyepoch;
+        }
+        if let Some(shelleyslot) = self.shelleyslot {
+            genesis.shelleyslot = shelleyslot;
+        }
+        if let Some(epochlength) = self.epochlength {
+            genesis.epochlength = epochlength;
+        }
+        if let Some(slotlength) = self.slotlength {
+            genesis.slotlength = slotlength;
+        }
+
+        self.genesis = genesis;
+    }
+
+    /// Settings of the monitor called `name`.
+    pub fn monitor(&self, name: &str) -> MonitorConfig {
+        self.monitors.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Whether an invite sent by `user_id` should be accepted.
+    pub fn invite_allowed(&self, user_id: &str) -> bool {
+        let server = user_id.split_once(':').map(|(_, server)| server);
+
+        self.inviteallow.iter().any(|allowed| allowed == user_id || Some(allowed.trim_start_matches(':')) == server)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+
+        let mut problems: Vec<String> = vec![];
+
+        let required = [
+            ("hostaddr", "HOST_ADDRESS", &self.hostaddr),
+            ("user", "DB_USER", &self.user),
+            ("password", "DB_PASSWORD", &self.password),
+            ("dbname", "DB_NAME", &self.dbname),
+            ("matrixuser", "MATRIX_USER", &self.matrixuser),
+            ("matrixpassword", "MATRIX_PASSWORD", &self.matrixpassword),
+            ("matrixhomeserver", "MATRIX_HOMESERVER", &self.matrixhomeserver),
+        ];
+
+        for (key, var, value) in required {
+            if value.trim().is_empty() {
+                problems.push(format!("{} is not set (config key `{}`)", var, key));
+            }
+        }
+
+        if self.operatorpowerlevel.zip(self.adminpowerlevel).is_some_and(|(operator, admin)| operator > admin) {
+            problems.push("OPERATOR_POWER_LEVEL must not be above ADMIN_POWER_LEVEL".to_owned());
+        }
+
+        if Genesis::network(&self.network).is_none() && self.shelleystart.is_none() {
+            problems.push(format!("network `{}` is unknown; set SHELLEY_START, SHELLEY_EPOCH, SHELLEY_SLOT, EPOCH_LENGTH and SLOT_LENGTH", self.network));
+        } else if self.genesis.epochlength == 0 || self.genesis.slotlength == 0 {
+            problems.push("EPOCH_LENGTH and SLOT_LENGTH must be set and above zero".to_owned());
+        }
+
+        for (name, monitor) in &self.monitors {
+            if !monitors::NAMES.contains(&name.as_str()) {
+                problems.push(format!("monitors: unknown monitor `{}`, expected one of {}", name, monitors::NAMES.join(", ")));
+            }
+            if monitor.interval == 0 || monitor.timeout == 0 {
+                problems.push(format!("monitors.{}: interval and timeout must be above zero", name));
+            }
+        }
+
+        for kind in &self.mutedevents {
+            if !event::KINDS.contains(&kind.as_str()) {
+                problems.push(format!("mutedevents: unknown event `{}`, expected one of {}", kind, event::KINDS.join(", ")));
+            }
+        }
+
+        for (i, notifier) in self.notifiers.iter().enumerate() {
+            let name = format!("notifiers[{}]", i);
+
+            let (required, urls) = match notifier.service.as_str() {
+                "matrix" => (vec![], vec![]),
+                "discord" | "slack" => (vec![("webhook", &notifier.webhook)], vec![&notifier.webhook]),
+                "telegram" => (vec![("bottoken", &notifier.bottoken), ("chatid", &notifier.chatid)], vec![&notifier.apiurl]),
+                service => {
+                    problems.push(format!("{}: unknown service `{}`, expected one of matrix, discord, telegram, slack", name, service));
+                    continue;
+                }
+            };
+
+            for (key, value) in required {
+                if value.trim().is_empty() {
+                    problems.push(format!("{}: {} is required for {}", name, key, notifier.service));
+                }
+            }
+            for url in urls {
+                if !url.is_empty() && Url::parse(url).is_err() {
+                    problems.push(format!("{}: `{}` is not a valid URL", name, url));
+                }
+            }
+            for ticker in &notifier.pools {
+                if !self.pools.iter().any(|pool| &pool.ticker == ticker) {
+                    problems.push(format!("{}: no pool with ticker {} is configured", name, ticker));
+                }
+            }
+            for kind in &notifier.events {
+                if !event::KINDS.contains(&kind.as_str()) {
+                    problems.push(format!("{}: unknown event `{}`, expected one of {}", name, kind, event::KINDS.join(", ")));
+                }
+            }
+        }
+
+        if self.port == 0 {
+            problems.push("DB_PORT is not set (config key `port`)".to_owned());
+        }
+
+        let mut tickers = HashSet::new();
+
+        for (i, pool) in self.pools.iter().enumerate() {
+            let name = if pool.ticker.is_empty() { format!("pools[{}]", i) } else { pool.ticker.clone() };
+
+            if pool.ticker.trim().is_empty() {
+                problems.push(format!("{}: ticker is not set (POOL_TICKER)", name));
+            } else if !tickers.insert(pool.ticker.as_str()) {
+                problems.push(format!("{}: ticker is configured more than once", name));
+            }
+            if pool.poolid.trim().is_empty() {
+                problems.push(format!("{}: poolid is not set (POOL_ID)", name));
+            }
+            if pool.matrixroom.trim().is_empty() {
+                problems.push(format!("{}: matrixroom is not set (MATRIX_ROOM)", name));
+            }
+            if pool.stake_buffer() < Decimal::ZERO {
+                problems.push(format!("{}: stakebuffer must not be negative", name));
+            }
+        }
+
+        if !self.matrixhomeserver.is_empty() && Url::parse(&self.matrixhomeserver).is_err() {
+            problems.push(format!("MATRIX_HOMESERVER `{}` is not a valid URL", self.matrixhomeserver));
+        }
+
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n  - {}", problems.join("\n  - "));
+        }
+
+        Ok(())
+    }
+
+}
+
+fn override_value<T>(field: &mut T, var: &str) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(value) = env::var(var) {
+        *field = value
+            .parse()
+            .map_err(|e| anyhow!("{} has an invalid value `{}`: {}", var, value, e))?;
+    }
+
+    Ok(())
+}
+
+/// Reads `var`, the old name of `replacement`, only when neither the file
+/// nor `replacement` set the value. Login shells set `USER` and the like, so
+/// these must never win over the config file.
+fn deprecated_value<T>(field: &mut T, var: &str, replacement: &str) -> anyhow::Result<()>
+where
+    T: FromStr + Default + PartialEq,
+    T::Err: std::fmt::Display,
+{
+    if *field != T::default() || env::var_os(var).is_none() {
+        return Ok(());
+    }
+
+    warn!("{} is deprecated, set {} instead", var, replacement);
+    override_value(field, var)
+}
+
+fn override_option<T>(field: &mut Option<T>, var: &str) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(value) = env::var(var) {
+        *field = Some(value
+            .parse()
+            .map_err(|e| anyhow!("{} has an invalid value `{}`: {}", var, value, e))?);
+    }
+
+    Ok(())
+}
+
+/// Replaces `field` with the comma separated entries of `var`, if it is set.
+fn override_list(field: &mut Vec<String>, var: &str) {
+    if let Ok(value) = env::var(var) {
+        *field = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_owned)
+            .collect();
+    }
+}
@@ -0,0 +1,374 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use log::{info, debug, warn};
+
+use rust_decimal::Decimal;
+
+use tokio::time::{self, Instant};
+use tokio_postgres::{NoTls, Row};
+use tokio_postgres::types::ToSql;
+
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod, Runtime};
+
+use crate::config::DatabaseConfig;
+
+const BLOCKS_FORGED_QUERY: &str = "Select epoch_no, blocks_forged from balance.bot_blocks_forged where pool_id = $1";
+const BLOCK_HISTORY_QUERY: &str = "Select b.epoch_no::bigint as epoch_no, count(*) as blocks_forged From block b join slot_leader sl on sl.id = b.slot_leader_id join pool_hash ph on ph.id = sl.pool_hash_id where ph.view = $1 and b.epoch_no >= $2::bigint group by b.epoch_no order by b.epoch_no desc";
+const SLOT_BLOCKS_QUERY: &str = "Select b.slot_no::bigint as slot_no, b.block_no::bigint as block_no, ph.view as pool_view From block b left join slot_leader sl on sl.id = b.slot_leader_id left join pool_hash ph on ph.id = sl.pool_hash_id where b.slot_no between $1::bigint and $2::bigint order by b.slot_no";
+const BLOCK_BEFORE_QUERY: &str = "Select b.slot_no::bigint as slot_no, b.block_no::bigint as block_no, ph.view as pool_view From block b left join slot_leader sl on sl.id = b.slot_leader_id left join pool_hash ph on ph.id = sl.pool_hash_id where b.slot_no < $1::bigint order by b.slot_no desc limit 1";
+const TIP_QUERY: &str = "Select max(slot_no)::bigint as slot_no, max(block_no)::bigint as block_no, null::text as pool_view From block";
+const LIFETIME_BLOCKS_QUERY: &str = "Select count(*) as lifetime_blocks From block b join slot_leader sl on sl.id = b.slot_leader_id join pool_hash ph on ph.id = sl.pool_hash_id where ph.view = $1";
+const DELEGATOR_LIST_QUERY: &str = "Select addr_view from balance.bot_delegator_list where pool_id = $1";
+const ADDRESS_VALUE_QUERY: &str = "Select * From balance.bot_address_value($1)";
+const LIVE_STAKE_QUERY: &str = "Select live_stake From balance.bot_live_stake where pool_id = $1";
+const STAKE_ADDRESS_QUERY: &str = "Select view as addr_view From stake_address where view = $1";
+const STAKE_ADDRESS_MATCH_QUERY: &str = "Select view as addr_view From stake_address where view like $1 order by view limit $2";
+const DELEGATION_QUERY: &str = "Select ph.view as pool_view, d.active_epoch_no From delegation d join stake_address sa on sa.id = d.addr_id join pool_hash ph on ph.id = d.pool_hash_id where sa.view = $1 and not exists (Select 1 From stake_deregistration sd where sd.addr_id = d.addr_id and sd.tx_id > d.tx_id) order by d.tx_id desc limit 1";
+const REWARD_HISTORY_QUERY: &str = "Select r.earned_epoch as epoch_no, sum(r.amount) / 1000000 as rewards From reward r join stake_address sa on sa.id = r.addr_id where sa.view = $1 group by r.earned_epoch order by r.earned_epoch desc limit $2";
+const POOL_STATS_QUERY: &str = "Select live_stake, live_saturation, live_delegator_count From balance.bot_pool_stats where pool_id = $1";
+
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+/// Characters after the `1` separator of a complete stake address: the
+/// header byte and key hash, plus the bech32 checksum.
+const STAKE_ADDRESS_DATA_LENGTH: usize = 53;
+
+/// Pooled db-sync connections. Broken connections are dropped when they are
+/// checked back out, so a restarted database is picked up again without
+/// restarting the bot.
+pub struct Database {
+    pool: Pool,
+    /// Upper bound on how long a caller waits for a healthy connection.
+    timeout: Duration,
+}
+
+/// Builds a value from a single result row. Implementations read their
+/// columns by name with `try_get`, so a missing or mistyped column is an
+/// error instead of a silently skipped value.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> anyhow::Result<Self>;
+}
+
+/// Blocks of one pool in one epoch. db-sync only knows the forged count;
+/// the others are `None` until something else tells the bot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Blocks {
+    pub epoch_no: i64,
+    pub blocks_forged: i64,
+    #[serde(default)]
+    pub assigned: Option<i64>,
+    #[serde(default)]
+    pub missed: Option<i64>,
+    #[serde(default)]
+    pub orphaned: Option<i64>,
+}
+
+/// A block on chain and the pool that forged it, if a pool did.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlotBlock {
+    pub slot_no: i64,
+    /// Height of the block, `None` for epoch boundary blocks.
+    pub block_no: Option<i64>,
+    pub pool_view: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LifetimeBlocks {
+    pub lifetime_blocks: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delegator {
+    pub addr_view: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Address {
+    pub stake_address: String,
+    pub ada_value: Decimal,
+    pub from_pool: String,
+    pub to_pool: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PoolStake {
+    pub live_stake: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    pub live_stake: Decimal,
+    pub live_saturation: Decimal,
+    pub live_delegator_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delegation {
+    pub pool_view: String,
+    pub active_epoch_no: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reward {
+    pub epoch_no: i64,
+    pub rewards: Decimal,
+}
+
+impl FromRow for Blocks {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            epoch_no: row.try_get("epoch_no")?,
+            blocks_forged: row.try_get("blocks_forged")?,
+            assigned: None,
+            missed: None,
+            orphaned: None,
+        })
+    }
+}
+
+impl FromRow for SlotBlock {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            slot_no: row.try_get("slot_no")?,
+            block_no: row.try_get("block_no")?,
+            pool_view: row.try_get("pool_view")?,
+        })
+    }
+}
+
+impl FromRow for LifetimeBlocks {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            lifetime_blocks: row.try_get("lifetime_blocks")?,
+        })
+    }
+}
+
+impl FromRow for Delegator {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            addr_view: row.try_get("addr_view")?,
+        })
+    }
+}
+
+impl FromRow for Address {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            stake_address: row.try_get("stake_address")?,
+            ada_value: row.try_get("ada_value")?,
+            from_pool: row.try_get("from_pool")?,
+            to_pool: row.try_get("to_pool")?,
+        })
+    }
+}
+
+impl FromRow for PoolStake {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            live_stake: row.try_get("live_stake")?,
+        })
+    }
+}
+
+impl FromRow for PoolStats {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            live_stake: row.try_get("live_stake")?,
+            live_saturation: row.try_get("live_saturation")?,
+            live_delegator_count: row.try_get("live_delegator_count")?,
+        })
+    }
+}
+
+impl FromRow for Delegation {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            pool_view: row.try_get("pool_view")?,
+            active_epoch_no: row.try_get("active_epoch_no")?,
+        })
+    }
+}
+
+impl FromRow for Reward {
+    fn from_row(row: &Row) -> anyhow::Result<Self> {
+        Ok(Self {
+            epoch_no: row.try_get("epoch_no")?,
+            rewards: row.try_get("rewards")?,
+        })
+    }
+}
+
+impl Database {
+
+    pub fn new(config: &DatabaseConfig) -> anyhow::Result<Self> {
+
+        let timeout = Duration::from_secs(config.dbtimeout);
+
+        let mut pg_config = tokio_postgres::Config::new();
+        pg_config
+            .host(&config.hostaddr)
+            .port(config.port)
+            .user(&config.user)
+            .password(&config.password)
+            .dbname(&config.dbname)
+            .connect_timeout(timeout);
+
+        let manager = Manager::from_config(pg_config, NoTls, ManagerConfig {
+            recycling_method: RecyclingMethod::Verified,
+        });
+
+        info!("Creating a new database pool");
+        let pool = Pool::builder(manager)
+            .max_size(config.dbpoolsize)
+            .wait_timeout(Some(timeout))
+            .create_timeout(Some(timeout))
+            .recycle_timeout(Some(timeout))
+            .runtime(Runtime::Tokio1)
+            .build()
+            .context("Unable to create database pool")?;
+
+        Ok(Self { pool, timeout })
+    }
+
+    /// Checks out a verified connection, retrying with exponential backoff
+    /// until the configured timeout has passed.
+    async fn client(&self) -> anyhow::Result<Object> {
+
+        let deadline = Instant::now() + self.timeout;
+        let mut backoff = Duration::from_millis(500);
+
+        loop {
+            match self.pool.get().await {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    if Instant::now() + backoff >= deadline {
+                        return Err(anyhow!("Database unavailable after {:?}: {}", self.timeout, e));
+                    }
+                    warn!("Database connection failed, retrying in {:?}: {}", backoff, e);
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let client = self.client().await?;
+        client.simple_query("SELECT 1;").await.context("Database health check failed")?;
+        debug!("Successfully connected to the database.");
+        Ok(())
+    }
+
+    pub async fn blocks_forged(&self, pool_id: &str) -> anyhow::Result<Vec<Blocks>> {
+        self.fetch(BLOCKS_FORGED_QUERY, &[&pool_id]).await
+    }
+
+    /// Blocks forged by the pool in each epoch from `from_epoch` on, newest
+    /// first. Epochs without a block have no row.
+    pub async fn block_history(&self, pool_id: &str, from_epoch: i64) -> anyhow::Result<Vec<Blocks>> {
+        self.fetch(BLOCK_HISTORY_QUERY, &[&pool_id, &from_epoch]).await
+    }
+
+    /// Blocks in slots `from` to `to`, inclusive, in slot order.
+    pub async fn slot_blocks(&self, from: i64, to: i64) -> anyhow::Result<Vec<SlotBlock>> {
+        self.fetch(SLOT_BLOCKS_QUERY, &[&from, &to]).await
+    }
+
+    /// The last block before `slot`, which a block in `slot` would have
+    /// been built on.
+    pub async fn block_before(&self, slot: i64) -> anyhow::Result<Vec<SlotBlock>> {
+        self.fetch(BLOCK_BEFORE_QUERY, &[&slot]).await
+    }
+
+    /// The newest block db-sync has seen.
+    pub async fn tip(&self) -> anyhow::Result<Vec<SlotBlock>> {
+        self.fetch(TIP_QUERY, &[]).await
+    }
+
+    pub async fn lifetime_blocks(&self, pool_id: &str) -> anyhow::Result<Vec<LifetimeBlocks>> {
+        self.fetch(LIFETIME_BLOCKS_QUERY, &[&pool_id]).await
+    }
+
+    pub async fn delegator_list(&self, pool_id: &str) -> anyhow::Result<Vec<Delegator>> {
+        self.fetch(DELEGATOR_LIST_QUERY, &[&pool_id]).await
+    }
+
+    pub async fn address_value(&self, stake_address: &str) -> anyhow::Result<Vec<Address>> {
+        self.fetch(ADDRESS_VALUE_QUERY, &[&stake_address]).await
+    }
+
+    /// Up to `limit` registered stake addresses starting with `prefix`.
+    /// db-sync only has a hash index on `stake_address.view`, so a complete
+    /// address is looked up as is and only a prefix scans the table.
+    pub async fn stake_address_matches(&self, prefix: &str, limit: i64) -> anyhow::Result<Vec<Delegator>> {
+
+        if complete_stake_address(prefix) {
+            return self.fetch(STAKE_ADDRESS_QUERY, &[&prefix]).await;
+        }
+
+        // `stake_test` addresses hold an underscore, a `LIKE` wildcard.
+        let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        self.fetch(STAKE_ADDRESS_MATCH_QUERY, &[&pattern, &limit]).await
+    }
+
+    /// The latest delegation certificate of `stake_address`, unless the
+    /// address was deregistered since.
+    pub async fn delegation(&self, stake_address: &str) -> anyhow::Result<Vec<Delegation>> {
+        self.fetch(DELEGATION_QUERY, &[&stake_address]).await
+    }
+
+    /// Rewards earned by `stake_address` in each of its last `epochs` epochs
+    /// with rewards, newest first.
+    pub async fn reward_history(&self, stake_address: &str, epochs: i64) -> anyhow::Result<Vec<Reward>> {
+        self.fetch(REWARD_HISTORY_QUERY, &[&stake_address, &epochs]).await
+    }
+
+    pub async fn live_stake(&self, pool_id: &str) -> anyhow::Result<Vec<PoolStake>> {
+        self.fetch(LIVE_STAKE_QUERY, &[&pool_id]).await
+    }
+
+    pub async fn pool_stats(&self, pool_id: &str) -> anyhow::Result<Vec<PoolStats>> {
+        self.fetch(POOL_STATS_QUERY, &[&pool_id]).await
+    }
+
+    /// Runs `query` with bound `params` and decodes every returned row as
+    /// `T`. Statements are prepared once per pooled connection and reused.
+    pub async fn fetch<T: FromRow>(&self, query: &str, params: &[&(dyn ToSql + Sync)]) -> anyhow::Result<Vec<T>> {
+
+        let client = self.client().await?;
+
+        let statement = client.prepare_cached(query).await
+            .with_context(|| format!("Unable to prepare: {}", query))?;
+
+        let rows = client.query(&statement, params).await
+            .with_context(|| format!("Query failed: {}", query))?;
+
+        debug!("Query returned {} rows", rows.len());
+
+        rows.iter()
+            .map(|row| T::from_row(row).with_context(|| format!("Unable to decode row from: {}", query)))
+            .collect()
+    }
+
+}
+
+/// Whether `address` is a whole stake address rather than the start of one.
+fn complete_stake_address(address: &str) -> bool {
+    address.rsplit_once('1').is_some_and(|(_, data)| data.len() >= STAKE_ADDRESS_DATA_LENGTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_complete_stake_addresses_from_prefixes() {
+        assert!(complete_stake_address("stake1u9f9v0z5zzlldgx58n8tklphu8mf7h4jvp2j2gddluemnssjfnkzz"));
+        assert!(complete_stake_address("stake_test1uqfu74w3wh4gfzu8m6e7j987h4lq9r3t7ef5gaw497uu85qsqfy27"));
+        assert!(!complete_stake_address("stake1u9f9v0z5zzlldgx58n8tk"));
+        assert!(!complete_stake_address("stake_test1uq"));
+    }
+}
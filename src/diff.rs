@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Differences between two keyed snapshots.
+pub struct Changes<'a, K, V> {
+    /// Keys only in the new snapshot.
+    pub added: Vec<(&'a K, &'a V)>,
+    /// Keys only in the old snapshot.
+    pub removed: Vec<(&'a K, &'a V)>,
+    /// Keys in both whose value changed, with the old and new value.
+    pub changed: Vec<(&'a K, &'a V, &'a V)>,
+}
+
+/// Compares `prev` with `cur` in one pass over each.
+pub fn diff<'a, K: Eq + Hash, V: PartialEq>(prev: &'a HashMap<K, V>, cur: &'a HashMap<K, V>) -> Changes<'a, K, V> {
+
+    let mut changes = Changes { added: vec![], removed: vec![], changed: vec![] };
+
+    for (key, value) in cur {
+        match prev.get(key) {
+            None => changes.added.push((key, value)),
+            Some(old) if old != value => changes.changed.push((key, old, value)),
+            Some(_) => {}
+        }
+    }
+
+    for (key, value) in prev {
+        if !cur.contains_key(key) {
+            changes.removed.push((key, value));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: &[(&'static str, i64)]) -> HashMap<&'static str, i64> {
+        entries.iter().copied().collect()
+    }
+
+    fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn identical_snapshots_have_no_changes() {
+        let prev = map(&[("a", 1), ("b", 2)]);
+        let changes = diff(&prev, &prev);
+
+        assert!(changes.added.is_empty());
+        assert!(changes.removed.is_empty());
+        assert!(changes.changed.is_empty());
+    }
+
+    #[test]
+    fn finds_added_keys() {
+        let (prev, cur) = (map(&[("a", 1)]), map(&[("a", 1), ("b", 2)]));
+        let changes = diff(&prev, &cur);
+
+        assert_eq!(changes.added, vec![(&"b", &2)]);
+        assert!(changes.removed.is_empty());
+        assert!(changes.changed.is_empty());
+    }
+
+    #[test]
+    fn finds_removed_keys() {
+        let (prev, cur) = (map(&[("a", 1), ("b", 2)]), map(&[("a", 1)]));
+        let changes = diff(&prev, &cur);
+
+        assert!(changes.added.is_empty());
+        assert_eq!(changes.removed, vec![(&"b", &2)]);
+        assert!(changes.changed.is_empty());
+    }
+
+    #[test]
+    fn finds_changed_values_with_old_and_new() {
+        let (prev, cur) = (map(&[("a", 1)]), map(&[("a", 3)]));
+        let changes = diff(&prev, &cur);
+
+        assert!(changes.added.is_empty());
+        assert!(changes.removed.is_empty());
+        assert_eq!(changes.changed, vec![(&"a", &1, &3)]);
+    }
+
+    #[test]
+    fn finds_several_changes_in_one_tick() {
+        let prev = map(&[("kept", 1), ("bumped", 1), ("gone", 1), ("also gone", 2)]);
+        let cur = map(&[("kept", 1), ("bumped", 2), ("new", 1), ("also new", 5)]);
+        let changes = diff(&prev, &cur);
+
+        assert_eq!(sorted(changes.added), vec![(&"also new", &5), (&"new", &1)]);
+        assert_eq!(sorted(changes.removed), vec![(&"also gone", &2), (&"gone", &1)]);
+        assert_eq!(changes.changed, vec![(&"bumped", &1, &2)]);
+    }
+
+    #[test]
+    fn empty_previous_snapshot_adds_everything() {
+        let (prev, cur) = (HashMap::new(), map(&[("a", 1), ("b", 2)]));
+        let changes = diff(&prev, &cur);
+
+        assert_eq!(sorted(changes.added), vec![(&"a", &1), (&"b", &2)]);
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+/// Shelley genesis timing of a Cardano network. Slot and epoch lengths are
+/// fixed from the first Shelley epoch on, so any later slot follows from it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Genesis {
+    /// Start of the first Shelley epoch.
+    pub shelleystart: DateTime<Utc>,
+    /// Number of the first Shelley epoch.
+    pub shelleyepoch: u64,
+    /// Absolute slot the first Shelley epoch starts at.
+    pub shelleyslot: u64,
+    /// Slots in an epoch.
+    pub epochlength: u64,
+    /// Seconds in a slot.
+    pub slotlength: u64,
+}
+
+/// Where `time` falls in the epoch schedule.
+pub struct EpochTime {
+    pub epoch: u64,
+    /// Absolute slot number.
+    pub slot: u64,
+    /// Slot within the epoch.
+    pub epochslot: u64,
+    pub epochlength: u64,
+    /// When the next epoch starts.
+    pub nextepoch: DateTime<Utc>,
+    pub remaining: Duration,
+}
+
+impl Genesis {
+
+    /// Timing of the public networks the bot knows by name.
+    pub fn network(name: &str) -> Option<Genesis> {
+        let (start, shelleyepoch, shelleyslot, epochlength) = match name {
+            "mainnet" => (1_596_059_091, 208, 4_492_800, 432_000),
+            "preprod" => (1_655_769_600, 4, 86_400, 432_000),
+            "preview" => (1_666_656_000, 0, 0, 86_400),
+            _ => return None,
+        };
+
+        Some(Genesis {
+            shelleystart: Utc.timestamp_opt(start, 0).single()?,
+            shelleyepoch,
+            shelleyslot,
+            epochlength,
+            slotlength: 1,
+        })
+    }
+
+    /// Epoch the absolute `slot` falls in.
+    pub fn epoch_of_slot(&self, slot: u64) -> u64 {
+        self.shelleyepoch + slot.saturating_sub(self.shelleyslot) / self.epochlength
+    }
+
+    pub fn epoch_at(&self, time: DateTime<Utc>) -> EpochTime {
+
+        let elapsed = (time - self.shelleystart).num_seconds().max(0) as u64;
+        let slots = elapsed / self.slotlength;
+        let epochs = slots / self.epochlength;
+
+        let nextepoch = self.shelleystart + Duration::seconds(((epochs + 1) * self.epochlength * self.slotlength) as i64);
+
+        EpochTime {
+            epoch: self.shelleyepoch + epochs,
+            slot: self.shelleyslot + slots,
+            epochslot: slots % self.epochlength,
+            epochlength: self.epochlength,
+            nextepoch,
+            remaining: nextepoch - time,
+        }
+    }
+
+}
+
+impl EpochTime {
+
+    pub fn progress(&self) -> f64 {
+        self.epochslot as f64 * 100.0 / self.epochlength as f64
+    }
+
+}
+
+/// `1d 4h 20m` style text for a duration of at least a minute.
+pub fn format_duration(duration: Duration) -> String {
+
+    let minutes = duration.num_minutes().max(0);
+    let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(genesis: &str, time: &str) -> EpochTime {
+        Genesis::network(genesis).unwrap().epoch_at(time.parse().unwrap())
+    }
+
+    #[test]
+    fn mainnet_epochs() {
+        let start = at("mainnet", "2024-07-28T21:44:51Z");
+        assert_eq!((start.epoch, start.slot, start.epochslot), (500, 130_636_800, 0));
+        assert_eq!(start.remaining, Duration::days(5));
+
+        let before = at("mainnet", "2024-07-28T21:44:50Z");
+        assert_eq!((before.epoch, before.epochslot), (499, 431_999));
+        assert_eq!(before.nextepoch, "2024-07-28T21:44:51Z".parse::<DateTime<Utc>>().unwrap());
+    }
+
+    #[test]
+    fn preprod_epochs() {
+        let time = at("preprod", "2023-10-14T12:00:00Z");
+        assert_eq!((time.epoch, time.slot, time.epochslot), (100, 41_601_600, 43_200));
+        assert_eq!(time.progress(), 10.0);
+    }
+
+    #[test]
+    fn preview_epochs() {
+        let time = at("preview", "2023-02-02T06:00:00Z");
+        assert_eq!((time.epoch, time.slot, time.epochslot), (100, 8_661_600, 21_600));
+        assert_eq!(time.remaining, Duration::hours(18));
+    }
+
+    #[test]
+    fn slots_and_times_agree() {
+        for network in ["mainnet", "preprod", "preview"] {
+            let genesis = Genesis::network(network).unwrap();
+            let time = genesis.epoch_at("2024-09-01T00:00:00Z".parse().unwrap());
+            assert_eq!(genesis.epoch_of_slot(time.slot), time.epoch, "{}", network);
+        }
+        assert!(Genesis::network("sancho").is_none());
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(Duration::seconds(59)), "0m");
+        assert_eq!(format_duration(Duration::minutes(125)), "2h 5m");
+        assert_eq!(format_duration(Duration::minutes(1440 + 61)), "1d 1h 1m");
+    }
+}
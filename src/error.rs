@@ -0,0 +1,71 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::Duration;
+
+/// Errors raised while polling db-sync or talking to Matrix. None of them are
+/// fatal: the scheduler logs the failure and retries on the next tick.
+#[derive(Debug)]
+pub enum BotError {
+    /// A query failed, or its rows could not be decoded.
+    Database(anyhow::Error),
+    /// A request to the homeserver or a webhook failed or was rejected.
+    Http(reqwest::Error),
+    /// The Matrix SDK refused to send a message.
+    Matrix(Box<matrix_sdk::Error>),
+    /// A query that should return a row returned none.
+    NoRows(&'static str),
+    /// The bot has not joined the room it was asked to post in.
+    UnknownRoom(String),
+    /// A monitor snapshot could not be saved.
+    State(anyhow::Error),
+    /// A monitor run took longer than its timeout.
+    Timeout(Duration),
+    /// The notification pipeline stopped taking events.
+    QueueClosed,
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Database(e) => write!(f, "database error: {:#}", e),
+            BotError::Http(e) => write!(f, "HTTP request failed: {}", e),
+            BotError::Matrix(e) => write!(f, "matrix error: {}", e),
+            BotError::NoRows(source) => write!(f, "no rows returned from {}", source),
+            BotError::UnknownRoom(room) => write!(f, "not joined to room {}", room),
+            BotError::State(e) => write!(f, "unable to save state: {:#}", e),
+            BotError::Timeout(timeout) => write!(f, "timed out after {:?}", timeout),
+            BotError::QueueClosed => write!(f, "notification queue closed"),
+        }
+    }
+}
+
+impl StdError for BotError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            BotError::Database(e) | BotError::State(e) => Some(e.as_ref()),
+            BotError::Http(e) => Some(e),
+            BotError::Matrix(e) => Some(e.as_ref()),
+            BotError::NoRows(_) | BotError::UnknownRoom(_) | BotError::Timeout(_) | BotError::QueueClosed => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for BotError {
+    fn from(e: anyhow::Error) -> Self {
+        BotError::Database(e)
+    }
+}
+
+impl From<reqwest::Error> for BotError {
+    /// Drops the request URL, which may carry an access token, so the error
+    /// is safe to log.
+    fn from(e: reqwest::Error) -> Self {
+        BotError::Http(e.without_url())
+    }
+}
+
+impl From<matrix_sdk::Error> for BotError {
+    fn from(e: matrix_sdk::Error) -> Self {
+        BotError::Matrix(Box::new(e))
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Utc};
+
+use indoc::formatdoc;
+
+use rust_decimal::Decimal;
+
+use serde::{Deserialize, Serialize};
+
+use thousands::{Separable, SeparatorPolicy, digits};
+
+use crate::epoch::format_duration;
+
+/// Something a monitor noticed about a pool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    BlockForged { epoch: i64, forged: i64, assigned: Option<i64> },
+    /// `height_battle` when a block at the height ours would have taken
+    /// followed shortly after, so the block may have lost a height battle.
+    BlockMissed {
+        epoch: i64,
+        slot: u64,
+        #[serde(default)]
+        height_battle: bool,
+    },
+    /// Another pool's block took the slot.
+    BattleLost { slot: u64, winner: Option<String> },
+    BlockExpected { slot: u64, at: DateTime<Utc> },
+    /// `ada` and the pools are `None` when they couldn't be looked up.
+    DelegatorArrived { stake_address: String, ada: Option<Decimal>, from_pool: Option<String> },
+    DelegatorDeparted { stake_address: String, ada: Option<Decimal>, to_pool: Option<String> },
+    StakeChanged { change: Decimal },
+}
+
+/// An event and the pool it is about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub ticker: String,
+    pub event: Event,
+}
+
+/// Every event kind, by the name used in config.
+pub const KINDS: &[&str] = &[
+    "block_forged",
+    "block_missed",
+    "battle_lost",
+    "block_expected",
+    "delegator_arrived",
+    "delegator_departed",
+    "stake_changed",
+];
+
+impl Event {
+
+    /// The name of the event's kind in config.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::BlockForged { .. } => "block_forged",
+            Event::BlockMissed { .. } => "block_missed",
+            Event::BattleLost { .. } => "battle_lost",
+            Event::BlockExpected { .. } => "block_expected",
+            Event::DelegatorArrived { .. } => "delegator_arrived",
+            Event::DelegatorDeparted { .. } => "delegator_departed",
+            Event::StakeChanged { .. } => "stake_changed",
+        }
+    }
+
+    /// Whether the event is no longer worth sending at `now`: a reminder
+    /// for a slot that has passed would read "Block expected in 0m".
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self, Event::BlockExpected { at, .. } if *at <= now)
+    }
+}
+
+impl Notification {
+
+    /// The message posted for the notification.
+    pub fn render(&self) -> String {
+
+        let ticker = &self.ticker;
+
+        let policy = SeparatorPolicy {
+            separator: ',',
+            groups:    &[3],
+            digits:    digits::ASCII_DECIMAL,
+        };
+
+        match &self.event {
+            Event::BlockForged { epoch, forged, assigned } => {
+                let assigned = assigned.map_or_else(|| "?".to_owned(), |assigned| assigned.to_string());
+                format!("⚒️   {}   {} / {}  blocks forged for epoch  {}", ticker, forged, assigned, epoch)
+            }
+            Event::BlockMissed { epoch, slot, height_battle: false } => format!("🚫   {}   Missed block in slot {} (epoch {})", ticker, slot, epoch),
+            Event::BlockMissed { epoch, slot, height_battle: true } => {
+                format!("🚫   {}   Missed block in slot {} (epoch {}, possible height battle)", ticker, slot, epoch)
+            }
+            Event::BattleLost { slot, winner } => {
+                format!("⚔️   {}   Lost slot battle in slot {} to {}", ticker, slot, winner.as_deref().unwrap_or("an unknown pool"))
+            }
+            Event::BlockExpected { slot, at } => {
+                format!("⏰   {}   Block expected in {}  (slot {}, {})", ticker, format_duration(*at - Utc::now()), slot, at.format("%H:%M UTC"))
+            }
+            Event::DelegatorArrived { stake_address, ada, from_pool } => {
+                let mut text = formatdoc!(r#"
+                    ✅   {}   {}Delegation Arriving   👏
+                        ▫️  Stake Address  {}"#, ticker, amount(ada, policy), short_address(stake_address));
+                if let Some(from_pool) = from_pool {
+                    text.push_str(&format!("\n    ▫️  From  {}", from_pool));
+                }
+                text
+            }
+            Event::DelegatorDeparted { stake_address, ada, to_pool } => {
+                let mut text = formatdoc!(r#"
+                    ❌   {}   {}Delegation Departing   🙏
+                        ▫️  Stake Address  {}"#, ticker, amount(ada, policy), short_address(stake_address));
+                if let Some(to_pool) = to_pool {
+                    text.push_str(&format!("\n    ▫️  To  {}", to_pool));
+                }
+                text
+            }
+            Event::StakeChanged { change } if change.is_sign_negative() => {
+                format!("❌   {}   Live Stake   ⬇️   {} ₳", ticker, change.separate_by_policy(policy))
+            }
+            Event::StakeChanged { change } => format!("✅   {}   Live Stake   ⬆️   {} ₳", ticker, change.separate_by_policy(policy)),
+        }
+    }
+}
+
+/// `1,000 ₳  ` ahead of a delegation line, or nothing when unknown.
+fn amount(ada: &Option<Decimal>, policy: SeparatorPolicy) -> String {
+    ada.map(|ada| format!("{} ₳  ", ada.separate_by_policy(policy))).unwrap_or_default()
+}
+
+fn short_address(stake_address: &str) -> &str {
+    stake_address.get(..10).unwrap_or(stake_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    fn render(event: Event) -> String {
+        Notification { ticker: "BALNC".to_owned(), event }.render()
+    }
+
+    #[test]
+    fn renders_forged_blocks_with_and_without_schedule() {
+        assert_eq!(render(Event::BlockForged { epoch: 480, forged: 3, assigned: Some(4) }), "⚒️   BALNC   3 / 4  blocks forged for epoch  480");
+        assert_eq!(render(Event::BlockForged { epoch: 480, forged: 3, assigned: None }), "⚒️   BALNC   3 / ?  blocks forged for epoch  480");
+    }
+
+    #[test]
+    fn renders_missed_blocks_and_battles() {
+        assert_eq!(render(Event::BlockMissed { epoch: 480, slot: 1000, height_battle: false }), "🚫   BALNC   Missed block in slot 1000 (epoch 480)");
+        assert_eq!(render(Event::BlockMissed { epoch: 480, slot: 1000, height_battle: true }),
+            "🚫   BALNC   Missed block in slot 1000 (epoch 480, possible height battle)");
+        assert_eq!(render(Event::BattleLost { slot: 1000, winner: Some("pool1rival".to_owned()) }),
+            "⚔️   BALNC   Lost slot battle in slot 1000 to pool1rival");
+        assert_eq!(render(Event::BattleLost { slot: 1000, winner: None }),
+            "⚔️   BALNC   Lost slot battle in slot 1000 to an unknown pool");
+    }
+
+    #[test]
+    fn renders_block_reminders() {
+        let at = Utc::now() + Duration::minutes(125) + Duration::seconds(30);
+        let text = render(Event::BlockExpected { slot: 1000, at });
+
+        assert!(text.starts_with("⏰   BALNC   Block expected in 2h 5m  (slot 1000, "), "{}", text);
+    }
+
+    #[test]
+    fn only_past_reminders_expire() {
+        let now = Utc::now();
+
+        assert!(Event::BlockExpected { slot: 1000, at: now - Duration::seconds(1) }.expired(now));
+        assert!(!Event::BlockExpected { slot: 1000, at: now + Duration::minutes(5) }.expired(now));
+        assert!(!Event::BlockMissed { epoch: 480, slot: 1000, height_battle: false }.expired(now));
+    }
+
+    #[test]
+    fn renders_delegators_with_and_without_details() {
+        let arrived = render(Event::DelegatorArrived {
+            stake_address: "stake1uxyz1234567890".to_owned(),
+            ada: Some(Decimal::new(1_234_567, 0)),
+            from_pool: Some("OTHER".to_owned()),
+        });
+        assert_eq!(arrived, "✅   BALNC   1,234,567 ₳  Delegation Arriving   👏\n    ▫️  Stake Address  stake1uxyz\n    ▫️  From  OTHER");
+
+        let departed = render(Event::DelegatorDeparted { stake_address: "stake1uxyz1234567890".to_owned(), ada: None, to_pool: None });
+        assert_eq!(departed, "❌   BALNC   Delegation Departing   🙏\n    ▫️  Stake Address  stake1uxyz");
+    }
+
+    #[test]
+    fn renders_stake_changes_by_direction() {
+        assert_eq!(render(Event::StakeChanged { change: Decimal::new(-250_000, 0) }), "❌   BALNC   Live Stake   ⬇️   -250,000 ₳");
+        assert_eq!(render(Event::StakeChanged { change: Decimal::new(250_000, 0) }), "✅   BALNC   Live Stake   ⬆️   250,000 ₳");
+    }
+}
@@ -1,86 +1,79 @@
-use std::collections::HashMap;
+mod commands;
+mod config;
+mod database;
+mod diff;
+mod epoch;
+mod error;
+mod event;
+mod monitor;
+mod monitors;
+mod notify;
+mod ratelimit;
+mod schedule;
+mod state;
+
+use std::fs;
+use std::path::Path;
 
-use std::fs::File;
-use std::io::Read;
 use std::vec::Vec;
 use std::error::Error as StdError;
-use std::pin::Pin;
-use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
-use std::{env, process::exit};
+
+use anyhow::Context;
 
 use serde::{Deserialize, Serialize};
-use serde_json;
 
-use log::{info, debug};
+use chrono::Utc;
 
 use reqwest::Url;
-use reqwest;
-use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
-
-use rust_decimal::Decimal;
 
-use thousands::{Separable, SeparatorPolicy, digits};
+use log::{error, info, warn};
 
 use matrix_sdk::{
     config::SyncSettings,
-    ruma::events::room::{
-        member::StrippedRoomMemberEvent,
-        message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+    encryption::EncryptionSettings,
+    event_handler::Ctx,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{
+        api::client::error::ErrorKind,
+        events::room::{
+            encrypted::OriginalSyncRoomEncryptedEvent,
+            member::{MembershipState, OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent},
+            message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+        OwnedDeviceId, OwnedUserId, RoomId,
     },
+    SessionMeta,
     Client as MatrixClient, Room, RoomState,
 };
 
-use indoc::formatdoc;
+use tokio::time;
 
-use tokio::{task, time};
-use tokio_postgres::{Client, Error, NoTls};
-use tokio_postgres::types::Type;
+use commands::{CommandContext, Parsed, Role};
+use config::{DatabaseConfig, PoolConfig};
+use database::Database;
+use error::BotError;
+use ratelimit::{CommandLimits, Throttle};
+use schedule::ScheduleStore;
+use monitor::MonitorContext;
+use state::{JoinedRoom, StateStore};
 
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
 
 
 
 
 
 
-
-struct Database {
-    client: Client,
-}
+/// Longest pause between attempts to resume a failed Matrix sync.
+const MAX_SYNC_BACKOFF: Duration = Duration::from_secs(60);
 
 struct Matrix {
-    
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-enum SqlValue {
-    Int(i32),
-    Bigint(i64),
-    Text(String),
-    // Date(NaiveDate),
-    Numeric(Decimal),
-    // Add more types here as needed.
-}
-
-#[derive(Debug, Deserialize)]
-struct DatabaseConfig {
-    hostaddr: String,
-    port: u16,
-    user: String,
-    password: String,
-    dbname: String,
-    matrixuser: String,
-    matrixpassword: String,
-    matrixroom: String,
-    matrixtoken: String,
-    matrixhomeserver: String,
-    slotschedule: u16,
+    client: MatrixClient,
+    state: Arc<StateStore>,
 }
 
-
+/// The Matrix login saved between restarts so the bot keeps a single device.
 #[derive(Serialize, Deserialize, Debug)]
 struct MatrixAuth {
     user_id: String,
@@ -89,655 +82,515 @@ struct MatrixAuth {
     device_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Blocks {
-    epoch_no: i64,
-    blocks_forged: i64,
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Delegator {
-    addr_view: String,
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Address {
-    stake_address: String,
-    ada_value: Decimal,
-    from_pool: String,
-    to_pool: String,
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PoolStake {
-    live_stake: Decimal,
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PoolStats {
-    live_stake: Decimal,
-    live_saturation: Decimal,
-    live_delegator_count: i64,
-}
 
+impl MatrixAuth {
+
+    fn from_session(home_server: &str, session: &MatrixSession) -> Self {
+        Self {
+            user_id: session.meta.user_id.to_string(),
+            access_token: session.tokens.access_token.clone(),
+            home_server: home_server.to_owned(),
+            device_id: session.meta.device_id.to_string(),
+        }
+    }
+
+    fn session(&self) -> anyhow::Result<MatrixSession> {
+        Ok(MatrixSession {
+            meta: SessionMeta {
+                user_id: OwnedUserId::try_from(self.user_id.as_str())?,
+                device_id: OwnedDeviceId::from(self.device_id.as_str()),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: self.access_token.clone(),
+                refresh_token: None,
+            },
+        })
+    }
+
+    /// `matrixuser` may be a bare localpart or a full `@user:server` id.
+    fn user_id_matches(&self, matrixuser: &str) -> bool {
+        self.user_id == matrixuser || self.user_id.trim_start_matches('@').split(':').next() == Some(matrixuser)
+    }
+
+    /// Compares the parsed URLs, so `https://matrix.org/` and
+    /// `https://matrix.org` are the same homeserver.
+    fn home_server_matches(&self, matrixhomeserver: &str) -> bool {
+        let parse = |url: &str| Url::parse(url.trim_end_matches('/')).ok();
+        match (parse(&self.home_server), parse(matrixhomeserver)) {
+            (Some(saved), Some(configured)) => saved == configured,
+            _ => self.home_server == matrixhomeserver,
+        }
+    }
+
+    fn load(path: &str) -> anyhow::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
 
+    /// Only the bot's user may read the file, it holds the access token.
+    fn save(&self, path: &str) -> anyhow::Result<()> {
+        state::write_private_json(Path::new(path), self, "Matrix session")
+    }
 
+}
 
 
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn StdError>>  {
 
-    tokio::task::spawn(async {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-            let matrix_homeserver = env::var("MATRIX_HOMESERVER").expect("Error: MATRIX_HOMESERVER not found");
-            let matrix_user = env::var("MATRIX_USER").expect("Error: MATRIX_USER not found");
-            let matrix_password = env::var("MATRIX_PASSWORD").expect("Error: MATRIX_PASSWORD not found");
-            
+    let config = Arc::new(DatabaseConfig::load()?);
+
+    let schedules = Arc::new(ScheduleStore::open(&config.schedulefile)?);
+
+    if import_schedule(&config, &schedules)? {
+        return Ok(());
+    }
+
+    let db = Arc::new(Database::new(&config)?);
+
+    let statestore = Arc::new(StateStore::open(&config.statefile)?);
+
+    let matrix = Arc::new(Matrix::new(&config, statestore.clone()).await?);
+
+    let matrix_config = config.clone();
+    let matrix_db = db.clone();
+    let matrix_schedules = schedules.clone();
+    let sync_matrix = matrix.clone();
+
+    tokio::task::spawn(async move {
 
             // tracing_subscriber::fmt::init();
             
-            Matrix::login_and_sync(matrix_homeserver, &matrix_user, &matrix_password).await?;
-            Ok::<(), anyhow::Error>(())
+            if let Err(e) = sync_matrix.sync(matrix_config, matrix_db, matrix_schedules).await {
+                error!("Matrix sync stopped: {:#}", e);
+            }
 
 
     });
 
 
-    //env::set_var("RUST_BACKTRACE", "full");
+    let (events, queue) = notify::channel();
+    let routes = notify::routes(&config, matrix.clone())?;
 
-    //env_logger::init();
+    tokio::task::spawn(notify::deliver(config.clone(), statestore.clone(), routes, queue));
 
-    let mut prevforged: Vec<HashMap<String, i64>> = vec![];
-    let mut prevdelegators: Vec<HashMap<String, String>> = vec![];
-    let mut prevpoolstake: Vec<HashMap<String, Decimal>> = vec![];
+    let jobs = monitors::register(&config, &statestore);
 
-    let mut interval = time::interval(Duration::from_secs(60));
+    let ctx = MonitorContext { config, db, matrix, schedules, state: statestore, events };
 
+    monitor::supervise(ctx, jobs).await;
 
-        loop {
-            interval.tick().await;
+    Ok(())
+}
 
-            let mut tasks = FuturesUnordered::<Pin<Box<dyn Future<Output = String>>>>::new();
-            
-            tasks.push(Box::pin(blocks(&mut prevforged)));
-            tasks.push(Box::pin(delegators(&mut prevdelegators)));
-            tasks.push(Box::pin(stake(&mut prevpoolstake)));
+    /// Handles `import-schedule <ticker> <file> [epoch]`, storing the leader
+    /// schedule in the file for the running bot to pick up. Returns whether
+    /// the command was given.
+    fn import_schedule(config: &DatabaseConfig, schedules: &ScheduleStore) -> anyhow::Result<bool> {
 
-            while let Some(result) = tasks.next().await {
-                println!("{}", result);
+        let args: Vec<String> = std::env::args().collect();
+        let Some(position) = args.iter().position(|arg| arg == "import-schedule") else { return Ok(false) };
 
-            }
-        }
-    }
+        let usage = "Usage: balance_bot import-schedule <ticker> <file> [epoch]";
+        let ticker = args.get(position + 1).ok_or_else(|| anyhow::anyhow!(usage))?;
+        let path = args.get(position + 2).ok_or_else(|| anyhow::anyhow!(usage))?;
+        let epoch = args.get(position + 3)
+            .map(|epoch| epoch.parse::<i64>().map_err(|_| anyhow::anyhow!("Invalid epoch `{}`", epoch)))
+            .transpose()?;
 
+        let pool = config.pools.iter()
+            .find(|pool| pool.ticker.eq_ignore_ascii_case(ticker))
+            .ok_or_else(|| anyhow::anyhow!("No pool with ticker {} is configured", ticker))?;
 
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Unable to read {}: {}", path, e))?;
+        let (epoch, schedule) = schedule::parse(&contents, &config.genesis, epoch)?;
+        let assigned = schedule.assigned;
 
-    
+        schedules.import(&pool.ticker, epoch, schedule)?;
 
-    async fn blocks(prevforged: &mut Vec<HashMap<String, i64>>) -> String {
-        let db = Database::new().await.expect("Problem with db connection");
-        db.ping().await;
-    
-        let curforged = db.fetch_block_data("Select * from balance.bot_blocks_forged").await.expect("Problem with pull latest block forge data");
-
-        if prevforged.is_empty() {
-            *prevforged = curforged.clone();
-            println!("Startup block forge data loaded");
-
-        } else {
-            let slots_assigned = env::var("SLOTS_ASSIGNED").expect("Error: SLOTS_ASSIGNED not found");
-            println!("Checking for block forge updates {}", slots_assigned);
-            let blockdiff: Vec<_> = curforged.into_iter().filter(|item| !prevforged.contains(item)).collect();
-
-            if blockdiff.is_empty() {
-                        println!(" -- No new blocks forged");
-                        
-                    } else {
-                        println!(" -- New blocks forged!  Sending message....");
-        
-                        
-                        let slots_assigned = env::var("SLOTS_ASSIGNED").expect("Error: SLOTS_ASSIGNED not found");
-        
-                        let map =  serde_json::to_string(&blockdiff[0]).unwrap();
-                        let p: Blocks = serde_json::from_str(&map).expect("REASON");
-                        
-                        let blockmsg: String = format!("⚒️   {} / {}  blocks forged for epoch  {}", p.blocks_forged, &slots_assigned, p.epoch_no);
-                       
-        
-                        Matrix::message(&blockmsg).await.expect("REASON");
-                        
-                        
-                        *prevforged = blockdiff.clone();
-                        
-                    }
-        }
-        "Task - Forged Blocks Complete".to_owned()
+        info!("Imported {} assigned slots for {} in epoch {}", assigned, pool.ticker, epoch);
+
+        Ok(true)
     }
 
-    async fn delegators(prevdelegators: &mut Vec<HashMap<String, String>>) -> String {
-        let db = Database::new().await.expect("Problem with db connection");
-        db.ping().await;
+impl Matrix {
 
-        let curdelegators = db.fetch_delegator_data("Select * from balance.bot_delegator_list").await.expect("Problem with pulling latest delegator data");
+    /// Connects to the configured homeserver and runs an initial sync so the
+    /// joined rooms are known before any alert is sent. A saved session is
+    /// restored when there is one; the password is only used when there is no
+    /// session or the homeserver rejects its token.
+    async fn new(config: &DatabaseConfig, state: Arc<StateStore>) -> anyhow::Result<Self> {
 
-            if prevdelegators.is_empty() {
-                *prevdelegators = curdelegators.clone();
-                println!("Startup delegator data loaded");
-            
-            } else {
-                println!("Checking for delegator updates");
-    
-                let arrivals: Vec<_> = curdelegators.clone().into_iter().filter(|item| !prevdelegators.contains(item)).collect();
-                let departures: Vec<_> = prevdelegators.clone().into_iter().filter(|item| !curdelegators.contains(item)).collect();
-    
-                if departures.is_empty() {
-                    println!(" -- No departures found");
-    
-                } else {
-    
-                    println!(" -- New departures found....processing");
-    
-                    for (i, row_map) in departures.iter().enumerate() {
-    
-                        for (key, value) in row_map.iter() {
-    
-                            let addressquery: String = format!("Select * From balance.bot_address_value('{}')", value);
-    
-                            let departuredata = db.fetch_address_data(&addressquery).await.expect("REASON");
-    
-                            let serialized = serde_json::to_string(&departuredata).unwrap();;
-                            let deserialized: Vec<Address> = serde_json::from_str(&serialized).unwrap();
-    
-                            let policy = SeparatorPolicy {
-                                separator: ',',
-                                groups:    &[3],
-                                digits:    digits::ASCII_DECIMAL,
-                            };
-    
-                            let ada = deserialized[0].ada_value.separate_by_policy(policy);
-                            let address = &deserialized[0].stake_address[..10];
-                            let newpool = &deserialized[0].to_pool;
-    
-                            let departuresmsg: String = formatdoc!(r#"
-                            ❌   {} ₳  Delegation Departing   🙏
-                                ▫️  Stake Address  {}
-                                ▫️  To  {}"#, ada, address, newpool);
-    
-                            Matrix::message(&departuresmsg).await.expect("REASON");
-    
-                            *prevdelegators = curdelegators.clone();
-    
-                            println!(" -- New departures send message complete");
-    
-                        }
-                    }
-    
-                }
-    
-                if arrivals.is_empty() {
-                    println!(" -- No arrivals found");
-    
-                } else {
-    
-                    println!(" -- New arrivals found....processing");
-    
-                    for (i, row_map) in arrivals.iter().enumerate() {
-    
-                        for (key, value) in row_map.iter() {
-    
-                            let addressquery: String = format!("Select * From balance.bot_address_value('{}')", value);
-    
-                            let arrivaldata = db.fetch_address_data(&addressquery).await.expect("REASON");
-    
-                            let serialized = serde_json::to_string(&arrivaldata).unwrap();;
-                            let deserialized: Vec<Address> = serde_json::from_str(&serialized).unwrap();
-    
-                            let policy = SeparatorPolicy {
-                                separator: ',',
-                                groups:    &[3],
-                                digits:    digits::ASCII_DECIMAL,
-                            };
-    
-                            let ada = deserialized[0].ada_value.separate_by_policy(policy);
-                            let address = &deserialized[0].stake_address[..10];
-                            let prevpool = &deserialized[0].from_pool;
-    
-                            if prevpool != "" {
-                                let arrivalsmsg: String = formatdoc!(r#"
-                                ✅   {} ₳  Delegation Arriving   👏 
-                                    ▫️  Stake Address  {}
-                                    ▫️  From  {}"#, ada, address, prevpool);
-    
-                                Matrix::message(&arrivalsmsg).await.expect("REASON");
-    
-                                *prevdelegators = curdelegators.clone();
-    
-                                println!(" -- New arrivals send message complete");
-    
-                            } else {
-                                let arrivalsmsg: String = formatdoc!(r#"
-                                ✅   {} ₳  Delegation Arriving   👏 
-                                    ▫️  Stake Address  {}"#, ada, address);
-    
-                                Matrix::message(&arrivalsmsg).await.expect("REASON");
-    
-                                *prevdelegators = curdelegators.clone();
-    
-                                println!(" -- New arrivals send message complete");
-    
-    
-                            }
-                        }
-                    }
-                }
-            }
+        let saved = MatrixAuth::load(&config.matrixsessionfile)?
+            .filter(|auth| auth.home_server_matches(&config.matrixhomeserver) && auth.user_id_matches(&config.matrixuser));
 
-        "Task - Delegators Complete".to_owned()
-    }
-
-    async fn stake(prevpoolstake: &mut Vec<HashMap<String, Decimal>>) -> String {
-        let db = Database::new().await.expect("Problem with db connection");
-        db.ping().await;
+        let client = match saved {
+            Some(auth) => match Matrix::restore(config, &auth).await? {
+                Some(client) => client,
+                None => Matrix::login(config, Some(&auth.device_id)).await?,
+            },
+            None => Matrix::login(config, None).await?,
+        };
 
-        let curpoolstake = db.fetch_poolstake_data("Select * From balance.bot_live_stake").await.expect("REASON");
+        client.sync_once(SyncSettings::default()).await?;
 
-        if prevpoolstake.is_empty() {
-            *prevpoolstake = curpoolstake.clone();
-            println!("Startup pool stake data loaded");
-            
-        } else {
-            println!("Checking for pool stake updates");
+        Ok(Self { client, state })
+    }
 
-            let stakediff: Vec<_> = curpoolstake.clone().into_iter().filter(|item| !prevpoolstake.contains(item)).collect();
+    /// The state and crypto stores live in `matrixstore`, so room keys survive
+    /// restarts and the bot can keep reading and posting in encrypted rooms.
+    async fn build_client(config: &DatabaseConfig) -> anyhow::Result<MatrixClient> {
 
-            let curstakeserialized2 = serde_json::to_string(&curpoolstake).unwrap();
-            let curstakedeserialized2: Vec<PoolStake> = serde_json::from_str(&curstakeserialized2).unwrap();
-            
+        let passphrase = Some(config.matrixstorepassphrase.as_str()).filter(|p| !p.is_empty());
 
-            if stakediff.is_empty() {
-                println!(" -- No live stake updates found");
+        Ok(MatrixClient::builder()
+            .homeserver_url(&config.matrixhomeserver)
+            .sqlite_store(&config.matrixstore, passphrase)
+            .with_encryption_settings(EncryptionSettings {
+                auto_enable_cross_signing: true,
+                ..EncryptionSettings::default()
+            })
+            .build()
+            .await?)
+    }
 
-            } else {
+    /// Returns a client using the saved session, or `None` if the homeserver
+    /// no longer accepts its access token.
+    async fn restore(config: &DatabaseConfig, auth: &MatrixAuth) -> anyhow::Result<Option<MatrixClient>> {
 
-                println!(" -- Pool stake updates found....processing");
+        let client = Matrix::build_client(config).await?;
+        client.restore_session(auth.session()?).await?;
 
-                let prevstakeserialized = serde_json::to_string(&prevpoolstake).unwrap();
-                let prevstakedeserialized: Vec<PoolStake> = serde_json::from_str(&prevstakeserialized).unwrap();
-                let curstakeserialized = serde_json::to_string(&curpoolstake).unwrap();
-                let curstakedeserialized: Vec<PoolStake> = serde_json::from_str(&curstakeserialized).unwrap();
-        
-                let diff = curstakedeserialized[0].live_stake - prevstakedeserialized[0].live_stake;
-                let negativebuffer = Decimal::new(-10000000, 2);
-                let positivebuffer = Decimal::new(10000000, 2);
-
-                let policy = SeparatorPolicy {
-                    separator: ',',
-                    groups:    &[3],
-                    digits:    digits::ASCII_DECIMAL,
-                };
+        match client.whoami().await {
+            Ok(_) => {
+                info!("restored Matrix session for {} on device {}", auth.user_id, auth.device_id);
+                Ok(Some(client))
+            }
+            Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. })) => {
+                warn!("Saved Matrix session was rejected, logging in with password");
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
 
-                if diff < negativebuffer  {
+    /// Logs in with the configured password, reusing `device_id` when given,
+    /// and saves the new session.
+    async fn login(config: &DatabaseConfig, device_id: Option<&str>) -> anyhow::Result<MatrixClient> {
+
+        // A new device can't use the keys of the old one, and the crypto
+        // store refuses to open for a different device. The old store is
+        // kept aside since its keys are the only copy for past messages.
+        if device_id.is_none() && Path::new(&config.matrixstore).exists() {
+            let aside = format!("{}.{}.bak", config.matrixstore.trim_end_matches('/'), Utc::now().format("%Y%m%d%H%M%S"));
+            warn!("No saved Matrix session, moving the store in {} to {}", config.matrixstore, aside);
+            fs::rename(&config.matrixstore, &aside)
+                .with_context(|| format!("Unable to move the Matrix store {} aside", config.matrixstore))?;
+        }
 
-                    let value = diff.separate_by_policy(policy);
-                    let totalvalue = curstakedeserialized[0].live_stake.separate_by_policy(policy);
-                    let stakemsg: String = format!("❌   Live Stake   ⬇️   {} ₳", value);
-                    
-                    Matrix::message(&stakemsg).await.expect("REASON");
-                    
-                    *prevpoolstake = curpoolstake.clone();
+        let client = Matrix::build_client(config).await?;
+    
+        let username = &config.matrixuser;
 
-                } else if diff > positivebuffer {
+        let mut login = client
+            .matrix_auth()
+            .login_username(username, &config.matrixpassword)
+            .initial_device_display_name("balance bot");
 
-                    let value = diff.separate_by_policy(policy);
-                    let totalvalue = curstakedeserialized[0].live_stake.separate_by_policy(policy);
-                    let stakemsg: String = format!("✅   Live Stake   ⬆️   {} ₳", value);
+        if let Some(device_id) = device_id {
+            login = login.device_id(device_id);
+        }
 
-                    Matrix::message(&stakemsg).await.expect("REASON");
-                    
-                    *prevpoolstake = curpoolstake.clone();
+        login.await?;
+    
+        info!("logged in as {username}");
 
-                } else {
-                    println!(" -- Pool stake delta inside buffer....waiting");
+        if let Some(session) = client.matrix_auth().session() {
+            let auth = MatrixAuth::from_session(&config.matrixhomeserver, &session);
 
-                }
+            if let Err(e) = auth.save(&config.matrixsessionfile) {
+                error!("Unable to save Matrix session: {:#}", e);
             }
         }
-        "Task - Pool Stake Complete".to_owned()
+
+        Ok(client)
     }
 
+    async fn message(&self, room: &str, query: &str) -> Result<(), BotError> {
 
+        let room = self.joined_room(room).ok_or_else(|| BotError::UnknownRoom(room.to_owned()))?;
 
-impl Database {
+        room.send(RoomMessageEventContent::text_plain(query)).await?;
 
-    async fn new() -> Result<Self, Error> {
+        Ok(())
+    }
 
-        let host_address = env::var("HOST_ADDRESS").expect("Error: HOST_ADDRESS not found");
-        let port = env::var("PORT").expect("Error: PORT not found");
-        let user = env::var("USER").expect("Error: USER not found");
-        let password = env::var("PASSWORD").expect("Error: PASSWORD not found");
-        let db_name = env::var("DB_NAME").expect("Error: DB_NAME not found");
-        
+    /// `room` if the bot is a member of it.
+    fn joined_room(&self, room: &str) -> Option<Room> {
+        RoomId::parse(room)
+            .ok()
+            .and_then(|room_id| self.client.get_room(&room_id))
+            .filter(|room| room.state() == RoomState::Joined)
+    }
 
-        let connect_params = format!(
-            "host={} port={} user={} password={} dbname={}",
-            host_address, port, user, password, db_name
-        );
+    /// Posts a pool alert to the pool's room and to every room that joined by
+    /// invitation. Every room is tried even if an earlier one fails, and a
+    /// failed room is retried on its own, so the others never get the alert
+    /// twice.
+    async fn announce(&self, pool: &PoolConfig, query: &str) -> Result<(), BotError> {
 
-        info!("Creating a new database instance");
-        let (client, connection) = tokio_postgres::connect(
-            &connect_params, NoTls).await?;
+        let mut rooms = vec![pool.matrixroom.clone()];
 
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("connection error: {}", e);
+        for room in self.state.rooms_for(&pool.ticker) {
+            if !rooms.contains(&room) {
+                rooms.push(room);
             }
-        });
+        }
 
-        Ok(Self { client })
-    }
+        let mut result = Ok(());
 
-    async fn ping(&self) -> Result<(), Error> {
-        match self.client.simple_query("SELECT 1;").await {
-            Ok(_) => {
-                //let timestamp = Utc::now();
-                println!("Successfully connected to the database.");
-                Ok(())
+        for room in rooms {
+            // Not worth retrying: the membership handler drops rooms the bot
+            // was removed from.
+            if self.joined_room(&room).is_none() {
+                error!("Unable to post {} alert to {}: not joined", pool.ticker, room);
+                result = Err(BotError::UnknownRoom(room));
+                continue;
             }
-            Err(e) => Err(e),
-        }
-    }
 
-    async fn fetch_block_data(&self, query: &str) -> Result<Vec<HashMap<String, i64>>, Box<dyn std::error::Error>> {
-        
-        let rows = self.client.query(query, &[]).await?;
-
-        let mut data: Vec<HashMap<String, i64>> = Vec::new();
-
-        debug!("Query returned {} rows", rows.len());
-        for row in rows.iter() {
-            let mut row_map = HashMap::new();
-            for column in row.columns() {
-                let column_name = column.name();
-                let type_ = column.type_();
-
-                let value = if *type_ == Type::INT4 {
-                    row.get(column_name)
-                // } else if *type_ == Type::TEXT || *type_ == Type::VARCHAR {
-                //     // Handle TEXT and VARCHAR types
-                //     SqlValue::Text(row.get(column_name))
-                // } else if *type_ == Type::DATE {
-                //     // Handle DATE type
-                //     SqlValue::Date(row.get(column_name))
-                } else if *type_ == Type::INT8 {
-                    row.get(column_name)
-                } else {
-                    // Handle other types or return an error.
-                    continue;
-                };
-                row_map.insert(column_name.to_string(), value);
+            if let Err(e) = notify::retry(&room, || self.message(&room, query)).await {
+                error!("Unable to post {} alert to {}: {}", pool.ticker, room, e);
+                result = Err(e);
             }
-            data.push(row_map);
         }
-        
-        Ok(data)
+
+        result
     }
 
-    async fn fetch_delegator_data(&self, query: &str) -> Result<Vec<HashMap<String, String>>, Box<dyn std::error::Error>> {
+    /// Handles incoming room messages until the sync loop stops, picking up
+    /// where the initial sync in `new` left off.
+    async fn sync(&self, config: Arc<DatabaseConfig>, db: Arc<Database>, schedules: Arc<ScheduleStore>) -> anyhow::Result<()> {
         
-        let rows = self.client.query(query, &[]).await?;
-
-        let mut data: Vec<HashMap<String, String>> = Vec::new();
-
-        debug!("Query returned {} rows", rows.len());
-        for row in rows.iter() {
-            let mut row_map = HashMap::new();
-            for column in row.columns() {
-                let column_name = column.name();
-                let type_ = column.type_();
-
-                let value = if *type_ == Type::INT4 {
-                    row.get(column_name)
-                } else if *type_ == Type::TEXT || *type_ == Type::VARCHAR {
-                    // Handle TEXT and VARCHAR types
-                    row.get(column_name)
-                // } else if *type_ == Type::DATE {
-                //     // Handle DATE type
-                //     SqlValue::Date(row.get(column_name))
-                } else if *type_ == Type::INT8 {
-                    row.get(column_name)
-                } else {
-                    // Handle other types or return an error.
-                    continue;
-                };
-                row_map.insert(column_name.to_string(), value);
+        self.client.add_event_handler_context(Arc::new(CommandLimits::new(&config)));
+        self.client.add_event_handler_context(config.clone());
+        self.client.add_event_handler_context(db);
+        self.client.add_event_handler_context(schedules);
+        self.client.add_event_handler_context(self.state.clone());
+        self.client.add_event_handler(Matrix::on_stripped_state_member);
+        self.client.add_event_handler(Matrix::on_room_member);
+        self.client.add_event_handler(Matrix::on_room_message);
+        self.client.add_event_handler(Matrix::on_undecryptable_message);
+
+        // Invites that came in with the initial sync, including any sent
+        // while the bot was down, arrived before the handlers existed.
+        for room in self.client.invited_rooms() {
+            match room.invite_details().await {
+                Ok(invite) => match invite.inviter {
+                    Some(inviter) => Matrix::accept_invite(room, inviter.user_id().to_string(), config.clone(), self.state.clone()).await,
+                    None => warn!("Ignoring invite to {} without a known inviter", room.room_id()),
+                },
+                Err(e) => error!("Unable to read invite to {}: {}", room.room_id(), e),
             }
-            data.push(row_map);
         }
-        
-        Ok(data)
-    }
 
-    async fn fetch_address_data(&self, query: &str) -> Result<Vec<HashMap<String, SqlValue>>, Box<dyn std::error::Error>> {
-        
-        let rows = self.client.query(query, &[]).await?;
-
-        let mut data: Vec<HashMap<String, SqlValue>> = Vec::new();
-
-        debug!("Query returned {} rows", rows.len());
-        for row in rows.iter() {
-            let mut row_map = HashMap::new();
-            for column in row.columns() {
-                let column_name = column.name();
-                let type_ = column.type_();
-
-                let value = if *type_ == Type::INT4 {
-                    SqlValue::Int(row.get(column_name))
-                } else if *type_ == Type::TEXT || *type_ == Type::VARCHAR {
-                    SqlValue::Text(row.get(column_name))
-                } else if *type_ == Type::INT8 {
-                    SqlValue::Bigint(row.get(column_name))
-                } else if *type_ == Type::NUMERIC {
-                    SqlValue::Numeric(row.get(column_name))
-                } else {
-                    // Handle other types or return an error.
-                    continue;
-                };
-                row_map.insert(column_name.to_string(), value);
+        // A failed sync ends `sync`, so it is started again with backoff; it
+        // resumes from the sync token in the store. The backoff starts over
+        // once a sync has run for a while.
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            let started = time::Instant::now();
+
+            if let Err(e) = self.client.sync(SyncSettings::default()).await {
+                if started.elapsed() > MAX_SYNC_BACKOFF {
+                    backoff = Duration::from_secs(1);
+                }
+                warn!("Matrix sync failed, resuming in {:?}: {}", backoff, e);
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_SYNC_BACKOFF);
             }
-            data.push(row_map);
         }
-        
-        Ok(data)
     }
 
-    async fn fetch_poolstake_data(&self, query: &str) -> Result<Vec<HashMap<String, Decimal>>, Box<dyn std::error::Error>> {
-        
-        let rows = self.client.query(query, &[]).await?;
-
-        let mut data: Vec<HashMap<String, Decimal>> = Vec::new();
-
-        debug!("Query returned {} rows", rows.len());
-        for row in rows.iter() {
-            let mut row_map = HashMap::new();
-            for column in row.columns() {
-                let column_name = column.name();
-                let type_ = column.type_();
-
-                let value = if *type_ == Type::INT4 {
-                    row.get(column_name)
-                } else if *type_ == Type::TEXT || *type_ == Type::VARCHAR {
-                    // Handle TEXT and VARCHAR types
-                    row.get(column_name)
-                // } else if *type_ == Type::DATE {
-                //     // Handle DATE type
-                //     SqlValue::Date(row.get(column_name))
-                } else if *type_ == Type::INT8 {
-                    row.get(column_name)
-                } else if *type_ == Type::NUMERIC {
-                    row.get(column_name)
-                } else {
-                    // Handle other types or return an error.
-                    continue;
-                };
-                row_map.insert(column_name.to_string(), value);
-            }
-            data.push(row_map);
+    /// Joins rooms the bot is invited to by an allowed user or server and
+    /// introduces itself. Homeservers can report an invite before the room
+    /// is joinable, so joining is retried with backoff.
+    async fn on_stripped_state_member(event: StrippedRoomMemberEvent, client: MatrixClient, room: Room, config: Ctx<Arc<DatabaseConfig>>, state: Ctx<Arc<StateStore>>) {
+
+        if client.user_id() != Some(&*event.state_key) || event.content.membership != MembershipState::Invite {
+            return;
         }
-        
-        Ok(data)
+
+        Matrix::accept_invite(room, event.sender.to_string(), config.0.clone(), state.0.clone()).await;
     }
 
-}
+    /// Forgets rooms the bot left, was kicked from or was banned from, so
+    /// alerts stop going there.
+    async fn on_room_member(event: OriginalSyncRoomMemberEvent, client: MatrixClient, room: Room, state: Ctx<Arc<StateStore>>) {
 
+        if client.user_id() != Some(&*event.state_key) {
+            return;
+        }
+        if !matches!(event.content.membership, MembershipState::Leave | MembershipState::Ban) {
+            return;
+        }
 
+        match state.remove_room(room.room_id().as_str()) {
+            Ok(true) => info!("Removed from {} by {}, no longer posting alerts there", room.room_id(), event.sender),
+            Ok(false) => {}
+            Err(e) => error!("Unable to forget room {}: {:#}", room.room_id(), e),
+        }
+    }
 
-impl Matrix {
+    /// Joins `room` if `inviter` is allowed to invite the bot, and declines
+    /// the invite otherwise.
+    async fn accept_invite(room: Room, inviter: String, config: Arc<DatabaseConfig>, state: Arc<StateStore>) {
 
-    async fn new() -> Result<MatrixAuth, Box<dyn StdError>> {
+        if !config.invite_allowed(&inviter) {
+            info!("Declining invite to {} from {}", room.room_id(), inviter);
 
-        let matrix_user = env::var("MATRIX_USER").expect("Error: MATRIX_USER not found");
-        let matrix_password = env::var("MATRIX_PASSWORD").expect("Error: MATRIX_PASSWORD not found");
+            if let Err(e) = room.leave().await {
+                error!("Unable to decline invite to {}: {}", room.room_id(), e);
+            }
+            return;
+        }
 
-        let mut map = HashMap::new();
-        map.insert("type", "m.login.password");
-        map.insert("user", &matrix_user);
-        map.insert("password", &matrix_password);
-        map.insert("device_id", "balance_bot_service");
+        tokio::spawn(async move {
+            let mut delay = 2;
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post("https://matrix.forum.balanceanalytics.io/_matrix/client/r0/login")
-            .header(CONTENT_TYPE, "application/json")
-            .header(ACCEPT, "application/json")
-            .json(&map)
-            .send()
-            .await
-            .unwrap();
+            while let Err(e) = room.join().await {
+                warn!("Failed to join {} ({}), retrying in {}s", room.room_id(), e, delay);
 
-            let authdata: MatrixAuth = response.json::<MatrixAuth>().await?;
-            println!("Matrix Access Token {}", authdata.access_token);
+                time::sleep(Duration::from_secs(delay)).await;
+                delay *= 2;
 
-        Ok(authdata)
-    }
+                if delay > 3600 {
+                    error!("Giving up joining {}", room.room_id());
+                    return;
+                }
+            }
 
-    async fn message(query: &str) -> Result<(), Box<dyn StdError>> {
+            info!("Joined {} at the invitation of {}", room.room_id(), inviter);
 
-        let matrix_room = env::var("MATRIX_ROOM").expect("Error: MATRIX_ROOM not found");
-        let matrix_token = env::var("MATRIX_TOKEN").expect("Error: MATRIX_TOKEN not found");
+            let tickers: Vec<String> = config.pools.iter().map(|pool| pool.ticker.clone()).collect();
 
-        let mut url: String = format!("https://matrix.forum.balanceanalytics.io/_matrix/client/r0/rooms/{}/send/m.room.message?access_token={}", matrix_room, matrix_token);
+            let joined = JoinedRoom {
+                room_id: room.room_id().to_string(),
+                inviter,
+                pools: tickers.clone(),
+            };
 
-        let mut map = HashMap::new();
-        map.insert("msgtype", "m.text");
-        map.insert("body", query);
+            if let Err(e) = state.add_room(joined) {
+                error!("Unable to record joined room {}: {:#}", room.room_id(), e);
+            }
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .header(CONTENT_TYPE, "application/json")
-            .header(ACCEPT, "application/json")
-            .json(&map)
-            .send()
-            .await
-            .unwrap();
+            let intromsg: String = format!("👋   Hello! I'll post block, delegation and live stake alerts for {} here.\n{}",
+                tickers.join(", "), commands::summary(&config.commandprefix, Role::Public));
 
-        Ok(())
+            if let Err(e) = room.send(RoomMessageEventContent::text_plain(intromsg)).await {
+                error!("Unable to greet {}: {}", room.room_id(), e);
+            }
+        });
     }
 
-    async fn login_and_sync(homeserver_url: String, username: &str, password: &str) -> anyhow::Result<()> {
-        
-        let client = MatrixClient::builder()
-            .homeserver_url(homeserver_url)
-            .build()
-            .await?;
-    
-        client
-            .matrix_auth()
-            .login_username(username, password)
-            .initial_device_display_name("getting started bot")
-            .await?;
-    
-        println!("logged in as {username}");
-    
-        let sync_token = client.sync_once(SyncSettings::default()).await.unwrap().next_batch;
-    
-        client.add_event_handler(Matrix::on_room_message);
-    
-        let settings = SyncSettings::default().token(sync_token);
-        
-        client.sync(settings).await?;
-    
-        Ok(())
+    /// Encrypted events reach this handler only when they could not be
+    /// decrypted, typically because the sender has not shared the room key
+    /// with the bot's device yet.
+    async fn on_undecryptable_message(event: OriginalSyncRoomEncryptedEvent, room: Room) {
+        warn!("Unable to decrypt {} from {} in {}", event.event_id, event.sender, room.room_id());
     }
 
-    async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
+    async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room, config: Ctx<Arc<DatabaseConfig>>, db: Ctx<Arc<Database>>, schedules: Ctx<Arc<ScheduleStore>>, state: Ctx<Arc<StateStore>>, limits: Ctx<Arc<CommandLimits>>) {
 
-        if room.state() != RoomState::Joined {
-            return;
-        }
-        let MessageType::Text(text_content) = event.content.msgtype else { return };
-    
-        if text_content.body.contains("!party") {
-            let content = RoomMessageEventContent::text_plain("🎉🎊🥳 let's PARTY!! 🥳🎊🎉");
-    
-            // println!("sending");
-    
-            room.send(content).await.unwrap();
-    
-            // println!("message sent");
+        if let Err(e) = Matrix::handle_room_message(event, &room, &config, &db, &schedules, &state, &limits).await {
+            error!("Unable to answer message in {}: {}", room.room_id(), e);
         }
+    }
 
-        if text_content.body.contains("!boo") {
-            let content = RoomMessageEventContent::text_plain("👻  Booooo!!  👻");
-    
-            // println!("sending");
-    
-            room.send(content).await.unwrap();
+    async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: &Room, config: &DatabaseConfig, db: &Database, schedules: &ScheduleStore, state: &StateStore, limits: &CommandLimits) -> Result<(), BotError> {
+
+        if room.state() != RoomState::Joined || event.sender == room.own_user_id() {
+            return Ok(());
+        }
+        let MessageType::Text(text_content) = event.content.msgtype else { return Ok(()) };
     
-            // println!("message sent");
+        let parsed = commands::parse(&config.commandprefix, &text_content.body);
+
+        if let Parsed::NotCommand = parsed {
+            return Ok(());
         }
 
-        if text_content.body.contains("!status") {
+        // Invalid commands count too, or they could be used to flood the room.
+        if let Throttle::Limited { retry_after, first } = limits.acquire(event.sender.as_str(), room.room_id().as_str()) {
+            if first {
+                let reply = format!("⏳   Slow down a little, try again in {}s", retry_after.as_secs().max(1));
+                room.send(RoomMessageEventContent::text_plain(reply)).await?;
+            }
+            return Ok(());
+        }
 
-            let db = Database::new().await.unwrap();
+        let reply = match parsed {
+            Parsed::NotCommand => return Ok(()),
+            Parsed::Invalid(reply) => reply,
+            Parsed::Command(spec, command) => {
+                let power_level = match room.get_member(&event.sender).await? {
+                    Some(member) => member.power_level(),
+                    None => 0,
+                };
+                let role = commands::role_for(config, event.sender.as_str(), power_level);
 
-            let poolstatsquery: String = format!("Select * From balance.bot_pool_stats");
+                let ctx = CommandContext { config, db, schedules, state, cache: &limits.cache, room_id: room.room_id().as_str(), role };
+                commands::execute(spec, command, &ctx).await?
+            }
+        };
 
-            let poolstatsdata = db.fetch_address_data(&poolstatsquery).await.unwrap();
+        room.send(RoomMessageEventContent::text_plain(reply)).await?;
 
-            let serialized = serde_json::to_string(&poolstatsdata).unwrap();;
-            let deserialized: Vec<PoolStats> = serde_json::from_str(&serialized).unwrap();
+        Ok(())
+    }
 
-            let policy = SeparatorPolicy {
-                separator: ',',
-                groups:    &[3],
-                digits:    digits::ASCII_DECIMAL,
-            };
+}
 
-            let live_stake = deserialized[0].live_stake.separate_by_policy(policy);
-            let live_saturation = &deserialized[0].live_saturation.separate_by_policy(policy);
-            let live_delegator_count = &deserialized[0].live_delegator_count;
 
-            let poolstatsmsg: String = formatdoc!(r#"
-            ⚖️    BALNC Pool Statistics   🧐
-                ▫️  Stake            {} ₳
-                ▫️  Saturation    {} %
-                ▫️  Delegates     {}"#, live_stake, live_saturation, live_delegator_count);
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let content = RoomMessageEventContent::text_plain(&poolstatsmsg);
-    
-            // println!("sending");
-    
-            room.send(content).await.unwrap();
-    
-            // println!("message sent");
+    fn auth(home_server: &str) -> MatrixAuth {
+        MatrixAuth {
+            user_id: "@balance:matrix.org".to_owned(),
+            access_token: String::new(),
+            home_server: home_server.to_owned(),
+            device_id: "BOTDEVICE".to_owned(),
         }
     }
 
-}
-
-
+    #[test]
+    fn home_server_ignores_trailing_slash() {
+        assert!(auth("https://matrix.org").home_server_matches("https://matrix.org/"));
+        assert!(auth("https://matrix.org/").home_server_matches("https://matrix.org"));
+        assert!(auth("https://Matrix.org").home_server_matches("https://matrix.org"));
+        assert!(auth("https://example.org/matrix/").home_server_matches("https://example.org/matrix"));
+        assert!(!auth("https://matrix.org").home_server_matches("https://example.org"));
+    }
 
+    #[test]
+    fn user_id_matches_localpart_or_full_id() {
+        assert!(auth("").user_id_matches("balance"));
+        assert!(auth("").user_id_matches("@balance:matrix.org"));
+        assert!(!auth("").user_id_matches("other"));
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use log::{error, info, warn};
+
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+use crate::config::{DatabaseConfig, MonitorConfig, PoolConfig};
+use crate::database::Database;
+use crate::error::BotError;
+use crate::event::{Event, Notification};
+use crate::schedule::ScheduleStore;
+use crate::state::{Pending, StateStore};
+use crate::Matrix;
+
+/// How often the database is checked while monitors run.
+const DB_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Pause before a crashed monitor is started again.
+const RESTART_DELAY: Duration = Duration::from_secs(30);
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Everything a monitor may read from or post to.
+pub struct MonitorContext {
+    pub config: Arc<DatabaseConfig>,
+    pub db: Arc<Database>,
+    /// Only for operational alerts; pool events go to `events`.
+    pub matrix: Arc<Matrix>,
+    pub schedules: Arc<ScheduleStore>,
+    pub state: Arc<StateStore>,
+    /// Queue of the notification pipeline.
+    pub events: mpsc::UnboundedSender<Pending>,
+}
+
+/// Watches one kind of pool data for changes worth announcing.
+///
+/// Each run fetches the current data, diffs it against the snapshot kept
+/// from the last run and hands the resulting events to the notification
+/// pipeline. The snapshot returned by `diff` is saved in the same write as
+/// the events, which stay in the state file's outbox until every route is
+/// done with them. Delivery happens after the snapshot moved on, so an event
+/// a route gives up on after its retries is not announced again; one still
+/// waiting when the bot stops is sent on the next start. The first run after a start
+/// without a saved snapshot diffs against `Snapshot::default()` and drops
+/// the events, so it only sets the baseline.
+pub trait Monitor: Send + Sync + 'static {
+    /// What is kept between runs and restarts.
+    type Snapshot: Default + Serialize + DeserializeOwned + Send + Sync;
+    /// What one fetch returns.
+    type Fetched: Send;
+    type Event: Send + Sync;
+
+    /// Name used in config and as part of the snapshot key.
+    const NAME: &'static str;
+    /// Name used in log lines.
+    const LABEL: &'static str;
+
+    fn fetch<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, prev: Option<&'a Self::Snapshot>) -> BoxFuture<'a, Result<Self::Fetched, BotError>>;
+
+    /// The events between `prev` and `cur`, and the snapshot to keep with
+    /// them.
+    fn diff(&self, ctx: &MonitorContext, pool: &PoolConfig, prev: &Self::Snapshot, cur: Self::Fetched) -> (Self::Snapshot, Vec<Self::Event>);
+
+    /// The event sent for a change, with anything its message needs looked
+    /// up. A lookup that fails leaves its fields unset rather than holding up
+    /// the run, so one bad change can't keep the snapshot from moving on.
+    fn resolve<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, event: &'a Self::Event) -> BoxFuture<'a, Event>;
+}
+
+/// A monitor bound to one pool, as the scheduler sees it.
+pub trait Job: Send {
+    fn label(&self) -> &str;
+    fn settings(&self) -> &MonitorConfig;
+    fn run<'a>(&'a mut self, ctx: &'a MonitorContext) -> BoxFuture<'a, Result<(), BotError>>;
+}
+
+struct Runner<M: Monitor> {
+    monitor: M,
+    pool: PoolConfig,
+    settings: MonitorConfig,
+    label: String,
+    /// Key of the snapshot in the state file.
+    key: String,
+    snapshot: Option<M::Snapshot>,
+}
+
+/// Key the snapshot of monitor `name` for `ticker` is saved under.
+pub fn snapshot_key(ticker: &str, name: &str) -> String {
+    format!("{}/{}", ticker, name)
+}
+
+/// Binds `monitor` to `pool`, picking up its saved snapshot.
+pub fn job<M: Monitor>(monitor: M, pool: &PoolConfig, settings: MonitorConfig, state: &StateStore) -> Box<dyn Job> {
+
+    let key = snapshot_key(&pool.ticker, M::NAME);
+
+    let snapshot = state.snapshot(&key).unwrap_or_else(|e| {
+        error!("Discarding saved {} snapshot: {:#}", key, e);
+        None
+    });
+
+    Box::new(Runner {
+        monitor,
+        pool: pool.clone(),
+        settings,
+        label: format!("{} {}", pool.ticker, M::LABEL),
+        key,
+        snapshot,
+    })
+}
+
+impl<M: Monitor> Job for Runner<M> {
+
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn settings(&self) -> &MonitorConfig {
+        &self.settings
+    }
+
+    fn run<'a>(&'a mut self, ctx: &'a MonitorContext) -> BoxFuture<'a, Result<(), BotError>> {
+        Box::pin(async move {
+
+            let fetched = self.monitor.fetch(ctx, &self.pool, self.snapshot.as_ref()).await?;
+
+            let Some(prev) = self.snapshot.as_ref() else {
+                let (snapshot, _) = self.monitor.diff(ctx, &self.pool, &M::Snapshot::default(), fetched);
+                info!("{} startup data loaded", self.label);
+                return self.commit(ctx, snapshot, vec![]);
+            };
+
+            let (snapshot, events) = self.monitor.diff(ctx, &self.pool, prev, fetched);
+
+            let mut notifications = Vec::with_capacity(events.len());
+
+            for event in &events {
+                let event = self.monitor.resolve(ctx, &self.pool, event).await;
+                notifications.push(Notification { ticker: self.pool.ticker.clone(), event });
+            }
+
+            self.commit(ctx, snapshot, notifications)
+        })
+    }
+
+}
+
+impl<M: Monitor> Runner<M> {
+
+    /// Saves the snapshot with the notifications it produced, then queues
+    /// them. They are in the outbox by then, so a closed queue only delays
+    /// them until the next start.
+    fn commit(&mut self, ctx: &MonitorContext, snapshot: M::Snapshot, notifications: Vec<Notification>) -> Result<(), BotError> {
+
+        let pending = ctx.state.commit(&self.key, &snapshot, notifications).map_err(BotError::State)?;
+        self.snapshot = Some(snapshot);
+
+        for pending in pending {
+            ctx.events.send(pending).map_err(|_| BotError::QueueClosed)?;
+        }
+
+        Ok(())
+    }
+
+}
+
+/// Runs every job in a task of its own, so a slow or hung monitor only
+/// holds up itself, and restarts any that panics. Also watches the database
+/// so no monitor runs while it is down.
+pub async fn supervise(ctx: MonitorContext, jobs: Vec<Box<dyn Job>>) {
+
+    let ctx = Arc::new(ctx);
+    let healthy = Arc::new(AtomicBool::new(true));
+
+    let mut tasks = JoinSet::new();
+
+    tasks.spawn(watch_database(ctx.clone(), healthy.clone()));
+
+    for job in jobs {
+        tasks.spawn(restart(ctx.clone(), healthy.clone(), Arc::new(Mutex::new(job))));
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        if let Err(e) = result {
+            error!("Monitor supervisor stopped: {}", e);
+        }
+    }
+}
+
+/// Keeps `job` running, starting it again after a panic. The job survives
+/// the panic behind its mutex, with the snapshot of its last complete run.
+async fn restart(ctx: Arc<MonitorContext>, healthy: Arc<AtomicBool>, job: Arc<Mutex<Box<dyn Job>>>) {
+
+    loop {
+        let Err(e) = tokio::spawn(drive(ctx.clone(), healthy.clone(), job.clone())).await else { return };
+
+        let label = job.lock().await.label().to_owned();
+        let reason = match e.try_into_panic() {
+            Ok(panic) => panic.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned()),
+            Err(e) => e.to_string(),
+        };
+
+        error!("Task - {} crashed, restarting in {:?}: {}", label, RESTART_DELAY, reason);
+        alert(&ctx, &format!("⚠️   {} monitor crashed and will restart: {}", label, reason)).await;
+
+        time::sleep(RESTART_DELAY).await;
+    }
+}
+
+/// Runs `job` every interval, plus jitter, for at most its timeout. Runs
+/// never overlap: one that overruns its interval makes the runs it missed be
+/// skipped rather than run back to back.
+async fn drive(ctx: Arc<MonitorContext>, healthy: Arc<AtomicBool>, job: Arc<Mutex<Box<dyn Job>>>) {
+
+    let mut failures: u64 = 0;
+
+    loop {
+        let mut job = job.lock().await;
+
+        let settings = job.settings().clone();
+        let interval = Duration::from_secs(settings.interval);
+        let timeout = Duration::from_secs(settings.timeout);
+        let started = Instant::now();
+
+        if healthy.load(Ordering::Relaxed) {
+            let result = match time::timeout(timeout, job.run(&ctx)).await {
+                Ok(result) => result,
+                Err(_) => Err(BotError::Timeout(timeout)),
+            };
+
+            match result {
+                Ok(()) => info!("Task - {} Complete", job.label()),
+                Err(e) => {
+                    failures += 1;
+                    error!("Task - {} Failed ({} failures so far): {}", job.label(), failures, e);
+                }
+            }
+        }
+
+        let elapsed = started.elapsed();
+        if elapsed > interval {
+            warn!("Task - {} took {:?}, skipping {} runs", job.label(), elapsed, elapsed.as_secs() / settings.interval);
+        }
+
+        drop(job);
+
+        let runs = elapsed.as_secs() / settings.interval + 1;
+        time::sleep_until(started + interval * runs as u32 + jitter(settings.jitter)).await;
+    }
+}
+
+/// Checks the database every `DB_CHECK_INTERVAL`, pausing the monitors while
+/// it is down and telling the alert room when it goes down and comes back.
+async fn watch_database(ctx: Arc<MonitorContext>, healthy: Arc<AtomicBool>) {
+
+    let mut interval = time::interval(DB_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match ctx.db.ping().await {
+            Err(e) => {
+                error!("Pausing monitors: {:#}", e);
+
+                if healthy.swap(false, Ordering::Relaxed) {
+                    alert(&ctx, "⚠️   Database unavailable, pool monitoring paused").await;
+                }
+            }
+            Ok(()) => {
+                if !healthy.swap(true, Ordering::Relaxed) {
+                    info!("Database connection restored");
+                    alert(&ctx, "✅   Database connection restored, pool monitoring resumed").await;
+                }
+            }
+        }
+    }
+}
+
+/// A random delay of up to `seconds`, so monitors sharing an interval don't
+/// all query at once.
+fn jitter(seconds: u64) -> Duration {
+    if seconds == 0 {
+        return Duration::ZERO;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % (seconds * 1000))
+}
+
+/// Posts to the alert room, if one is configured.
+async fn alert(ctx: &MonitorContext, msg: &str) {
+    if ctx.config.dbalertroom.is_empty() {
+        return;
+    }
+
+    if let Err(e) = ctx.matrix.message(&ctx.config.dbalertroom, msg).await {
+        error!("Unable to send alert: {}", e);
+    }
+}
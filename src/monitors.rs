@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use rust_decimal::Decimal;
+
+use chrono::{DateTime, Duration, Utc};
+
+use log::error;
+
+use crate::config::{DatabaseConfig, PoolConfig};
+use crate::database::{Delegator, SlotBlock};
+use crate::diff;
+use crate::error::BotError;
+use crate::event::Event;
+use crate::monitor::{self, BoxFuture, Job, Monitor, MonitorContext};
+use crate::schedule::LeaderSlot;
+use crate::state::StateStore;
+
+/// Every monitor the bot knows, by the name used in config.
+pub const NAMES: &[&str] = &[ForgedBlocks::NAME, MissedBlocks::NAME, BlockReminders::NAME, Delegators::NAME, PoolStake::NAME];
+
+/// Slots after a leader slot in which a block at the height ours would have
+/// taken makes a miss a possible height battle.
+const BATTLE_WINDOW: i64 = 10;
+
+/// One job per enabled monitor and pool. Reminders are only sent when
+/// `reminderminutes` is set.
+pub fn register(config: &DatabaseConfig, state: &StateStore) -> Vec<Box<dyn Job>> {
+
+    let mut jobs = vec![];
+
+    for pool in &config.pools {
+        for name in NAMES {
+            let settings = config.monitor(name);
+
+            if !settings.enabled || (*name == BlockReminders::NAME && config.reminderminutes == 0) {
+                continue;
+            }
+
+            jobs.push(match *name {
+                ForgedBlocks::NAME => monitor::job(ForgedBlocks, pool, settings, state),
+                MissedBlocks::NAME => monitor::job(MissedBlocks, pool, settings, state),
+                BlockReminders::NAME => monitor::job(BlockReminders, pool, settings, state),
+                Delegators::NAME => monitor::job(Delegators, pool, settings, state),
+                PoolStake::NAME => monitor::job(PoolStake, pool, settings, state),
+                other => unreachable!("monitor `{}` is in NAMES but has no job", other),
+            });
+        }
+    }
+
+    jobs
+}
+
+/// Announces every epoch whose forged count went up.
+pub struct ForgedBlocks;
+
+impl Monitor for ForgedBlocks {
+    /// Blocks forged by epoch.
+    type Snapshot = HashMap<i64, i64>;
+    type Fetched = HashMap<i64, i64>;
+    /// An epoch and its new forged count.
+    type Event = (i64, i64);
+
+    const NAME: &'static str = "blocks";
+    const LABEL: &'static str = "Forged Blocks";
+
+    fn fetch<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, _: Option<&'a Self::Snapshot>) -> BoxFuture<'a, Result<Self::Fetched, BotError>> {
+        Box::pin(async move {
+            Ok(ctx.db.blocks_forged(&pool.poolid).await?.into_iter()
+                .map(|blocks| (blocks.epoch_no, blocks.blocks_forged))
+                .collect())
+        })
+    }
+
+    fn diff(&self, _: &MonitorContext, _: &PoolConfig, prev: &Self::Snapshot, cur: Self::Fetched) -> (Self::Snapshot, Vec<Self::Event>) {
+
+        let changes = diff::diff(prev, &cur);
+
+        let mut forged: Vec<(i64, i64)> = changes.added.iter().map(|(epoch, count)| (**epoch, **count))
+            .chain(changes.changed.iter().filter(|(_, old, new)| new > old).map(|(epoch, _, count)| (**epoch, **count)))
+            .collect();
+        forged.sort_unstable();
+
+        (cur, forged)
+    }
+
+    fn resolve<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, event: &'a Self::Event) -> BoxFuture<'a, Event> {
+        Box::pin(async move {
+            let (epoch, forged) = *event;
+            Event::BlockForged { epoch, forged, assigned: ctx.schedules.assigned(&pool.ticker, epoch) }
+        })
+    }
+}
+
+/// Leader slots of one epoch that didn't end up on chain.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockOutcomes {
+    /// Slots without any block from the pool.
+    pub missed: i64,
+    /// Slots another pool's block took in a slot battle.
+    pub orphaned: i64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MissedSnapshot {
+    /// Highest leader slot checked.
+    pub lastchecked: u64,
+    /// Slots that produced no block on chain, keyed by epoch.
+    pub outcomes: BTreeMap<i64, BlockOutcomes>,
+}
+
+/// What became of a leader slot without a block from the pool.
+pub enum SlotOutcome {
+    /// db-sync keeps no orphaned blocks, so a miss followed shortly by a
+    /// block at the height ours would have taken can't be told apart from a
+    /// lost height battle; `height_battle` flags those.
+    Missed { height_battle: bool },
+    /// Another pool's block is in the slot itself.
+    Lost(SlotBlock),
+}
+
+pub struct CheckedSlots {
+    /// Highest leader slot db-sync is far enough past to check.
+    lastdue: u64,
+    /// Checked slots that didn't get the pool's block.
+    failed: Vec<(LeaderSlot, SlotOutcome)>,
+}
+
+/// What became of leader slot `slot_no`, given the block before it and the
+/// blocks from it to `BATTLE_WINDOW` slots after, none of them the pool's.
+/// Only a block in the slot itself certainly beat ours.
+fn slot_outcome(slot_no: i64, parent: Option<&SlotBlock>, following: &[SlotBlock]) -> SlotOutcome {
+
+    if let Some(block) = following.iter().find(|block| block.slot_no == slot_no) {
+        return SlotOutcome::Lost(block.clone());
+    }
+
+    let height = parent.and_then(|parent| parent.block_no).map(|block_no| block_no + 1);
+
+    let height_battle = height.is_some() && following.iter().any(|block| block.slot_no > slot_no && block.block_no == height);
+
+    SlotOutcome::Missed { height_battle }
+}
+
+/// Checks each leader slot once db-sync is past it, and alerts when the
+/// pool's block isn't on chain.
+pub struct MissedBlocks;
+
+impl Monitor for MissedBlocks {
+    type Snapshot = MissedSnapshot;
+    type Fetched = CheckedSlots;
+    type Event = (i64, LeaderSlot, SlotOutcome);
+
+    const NAME: &'static str = "missed";
+    const LABEL: &'static str = "Missed Blocks";
+
+    /// Only slots after the last checked one are looked up; the baseline
+    /// run looks up none.
+    fn fetch<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, prev: Option<&'a Self::Snapshot>) -> BoxFuture<'a, Result<Self::Fetched, BotError>> {
+        Box::pin(async move {
+
+            let tips = ctx.db.tip().await?;
+            let tip = tips.first().ok_or(BotError::NoRows("block"))?.slot_no;
+
+            let due: Vec<LeaderSlot> = ctx.schedules.slots_between(&pool.ticker, DateTime::<Utc>::MIN_UTC, Utc::now()).into_iter()
+                .filter(|slot| slot.slot as i64 + BATTLE_WINDOW <= tip)
+                .collect();
+
+            let lastdue = due.last().map_or(0, |slot| slot.slot);
+            let mut failed = vec![];
+
+            let Some(prev) = prev else { return Ok(CheckedSlots { lastdue, failed }) };
+
+            for slot in due.into_iter().filter(|slot| slot.slot > prev.lastchecked) {
+
+                let slot_no = slot.slot as i64;
+                let following = ctx.db.slot_blocks(slot_no, slot_no + BATTLE_WINDOW).await?;
+
+                if following.iter().any(|block| block.slot_no == slot_no && block.pool_view.as_deref() == Some(pool.poolid.as_str())) {
+                    continue;
+                }
+
+                let parent = ctx.db.block_before(slot_no).await?;
+                let outcome = slot_outcome(slot_no, parent.first(), &following);
+                failed.push((slot, outcome));
+            }
+
+            Ok(CheckedSlots { lastdue, failed })
+        })
+    }
+
+    fn diff(&self, ctx: &MonitorContext, _: &PoolConfig, prev: &Self::Snapshot, cur: Self::Fetched) -> (Self::Snapshot, Vec<Self::Event>) {
+
+        let mut outcomes = prev.outcomes.clone();
+        let mut events = vec![];
+
+        for (slot, outcome) in cur.failed {
+            let epoch = ctx.config.genesis.epoch_of_slot(slot.slot) as i64;
+            let counts = outcomes.entry(epoch).or_default();
+
+            match outcome {
+                SlotOutcome::Missed { .. } => counts.missed += 1,
+                SlotOutcome::Lost(_) => counts.orphaned += 1,
+            }
+            events.push((epoch, slot, outcome));
+        }
+
+        // The first run starts from the last slot due, so a fresh start
+        // doesn't report the whole imported history.
+        let lastchecked = prev.lastchecked.max(cur.lastdue).max(1);
+
+        (MissedSnapshot { lastchecked, outcomes }, events)
+    }
+
+    fn resolve<'a>(&'a self, _: &'a MonitorContext, _: &'a PoolConfig, event: &'a Self::Event) -> BoxFuture<'a, Event> {
+        Box::pin(async move {
+            let (epoch, slot, outcome) = event;
+
+            match outcome {
+                SlotOutcome::Lost(block) => Event::BattleLost { slot: slot.slot, winner: block.pool_view.clone() },
+                SlotOutcome::Missed { height_battle } => Event::BlockMissed { epoch: *epoch, slot: slot.slot, height_battle: *height_battle },
+            }
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReminderSnapshot {
+    /// Highest leader slot a reminder was sent for.
+    pub lastreminded: u64,
+}
+
+/// Reminds the pool's rooms of leader slots coming up within
+/// `reminderminutes`, once per slot.
+pub struct BlockReminders;
+
+impl Monitor for BlockReminders {
+    type Snapshot = ReminderSnapshot;
+    type Fetched = Vec<LeaderSlot>;
+    type Event = LeaderSlot;
+
+    const NAME: &'static str = "reminders";
+    const LABEL: &'static str = "Block Reminders";
+
+    fn fetch<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, _: Option<&'a Self::Snapshot>) -> BoxFuture<'a, Result<Self::Fetched, BotError>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let until = now + Duration::minutes(ctx.config.reminderminutes as i64);
+
+            Ok(ctx.schedules.slots_between(&pool.ticker, now, until))
+        })
+    }
+
+    fn diff(&self, _: &MonitorContext, _: &PoolConfig, prev: &Self::Snapshot, cur: Self::Fetched) -> (Self::Snapshot, Vec<Self::Event>) {
+
+        let upcoming: Vec<LeaderSlot> = cur.into_iter().filter(|slot| slot.slot > prev.lastreminded).collect();
+        let lastreminded = upcoming.last().map_or(prev.lastreminded, |slot| slot.slot);
+
+        (ReminderSnapshot { lastreminded }, upcoming)
+    }
+
+    fn resolve<'a>(&'a self, _: &'a MonitorContext, _: &'a PoolConfig, slot: &'a Self::Event) -> BoxFuture<'a, Event> {
+        Box::pin(async move {
+            Event::BlockExpected { slot: slot.slot, at: slot.at }
+        })
+    }
+}
+
+pub enum DelegatorChange {
+    Arrived(String),
+    Departed(String),
+}
+
+/// Announces every delegator that arrived or left.
+pub struct Delegators;
+
+impl Monitor for Delegators {
+    /// Delegators by stake address.
+    type Snapshot = HashMap<String, Delegator>;
+    type Fetched = HashMap<String, Delegator>;
+    type Event = DelegatorChange;
+
+    const NAME: &'static str = "delegators";
+    const LABEL: &'static str = "Delegators";
+
+    fn fetch<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, _: Option<&'a Self::Snapshot>) -> BoxFuture<'a, Result<Self::Fetched, BotError>> {
+        Box::pin(async move {
+            Ok(ctx.db.delegator_list(&pool.poolid).await?.into_iter()
+                .map(|delegator| (delegator.addr_view.clone(), delegator))
+                .collect())
+        })
+    }
+
+    fn diff(&self, _: &MonitorContext, _: &PoolConfig, prev: &Self::Snapshot, cur: Self::Fetched) -> (Self::Snapshot, Vec<Self::Event>) {
+
+        let changes = diff::diff(prev, &cur);
+
+        let events = changes.removed.iter().map(|(address, _)| DelegatorChange::Departed((*address).clone()))
+            .chain(changes.added.iter().map(|(address, _)| DelegatorChange::Arrived((*address).clone())))
+            .collect();
+
+        (cur, events)
+    }
+
+    /// Looks up the delegator's balance and the pools it moved between. An
+    /// address the lookup has no row for, such as a deregistered one, is
+    /// still announced, without them.
+    fn resolve<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, event: &'a Self::Event) -> BoxFuture<'a, Event> {
+        Box::pin(async move {
+
+            let stake_address = match event {
+                DelegatorChange::Arrived(address) | DelegatorChange::Departed(address) => address,
+            };
+
+            let addressdata = match ctx.db.address_value(stake_address).await {
+                Ok(addresses) => addresses.into_iter().next(),
+                Err(e) => {
+                    error!("Unable to look up {} delegator {}: {:#}", pool.ticker, stake_address, e);
+                    None
+                }
+            };
+
+            let ada = addressdata.as_ref().map(|data| data.ada_value);
+            let pool_of = |pool: Option<&String>| pool.filter(|pool| !pool.is_empty()).cloned();
+
+            match event {
+                DelegatorChange::Departed(_) => Event::DelegatorDeparted {
+                    stake_address: stake_address.clone(),
+                    ada,
+                    to_pool: pool_of(addressdata.as_ref().map(|data| &data.to_pool)),
+                },
+                DelegatorChange::Arrived(_) => Event::DelegatorArrived {
+                    stake_address: stake_address.clone(),
+                    ada,
+                    from_pool: pool_of(addressdata.as_ref().map(|data| &data.from_pool)),
+                },
+            }
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StakeSnapshot {
+    /// Live stake when it was last announced.
+    pub live_stake: Option<Decimal>,
+}
+
+/// Announces live stake movements larger than the pool's `stakebuffer`.
+/// Smaller movements add up until they pass it.
+pub struct PoolStake;
+
+impl Monitor for PoolStake {
+    type Snapshot = StakeSnapshot;
+    type Fetched = Decimal;
+    /// The change in live stake.
+    type Event = Decimal;
+
+    const NAME: &'static str = "stake";
+    const LABEL: &'static str = "Pool Stake";
+
+    fn fetch<'a>(&'a self, ctx: &'a MonitorContext, pool: &'a PoolConfig, _: Option<&'a Self::Snapshot>) -> BoxFuture<'a, Result<Self::Fetched, BotError>> {
+        Box::pin(async move {
+            let poolstake = ctx.db.live_stake(&pool.poolid).await?;
+            Ok(poolstake.first().ok_or(BotError::NoRows("balance.bot_live_stake"))?.live_stake)
+        })
+    }
+
+    fn diff(&self, _: &MonitorContext, pool: &PoolConfig, prev: &Self::Snapshot, cur: Self::Fetched) -> (Self::Snapshot, Vec<Self::Event>) {
+
+        let Some(prevstake) = prev.live_stake else {
+            return (StakeSnapshot { live_stake: Some(cur) }, vec![]);
+        };
+
+        let diff = cur - prevstake;
+
+        if diff.abs() > pool.stake_buffer() {
+            (StakeSnapshot { live_stake: Some(cur) }, vec![diff])
+        } else {
+            (StakeSnapshot { live_stake: Some(prevstake) }, vec![])
+        }
+    }
+
+    fn resolve<'a>(&'a self, _: &'a MonitorContext, _: &'a PoolConfig, change: &'a Self::Event) -> BoxFuture<'a, Event> {
+        Box::pin(async move {
+            Event::StakeChanged { change: *change }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(slot_no: i64, block_no: i64, pool: &str) -> SlotBlock {
+        SlotBlock { slot_no, block_no: Some(block_no), pool_view: Some(pool.to_owned()) }
+    }
+
+    #[test]
+    fn block_in_the_slot_is_a_lost_slot_battle() {
+        let parent = block(90, 500, "pool1parent");
+        let outcome = slot_outcome(100, Some(&parent), &[block(100, 501, "pool1rival")]);
+
+        assert!(matches!(outcome, SlotOutcome::Lost(block) if block.slot_no == 100));
+    }
+
+    #[test]
+    fn later_block_at_our_height_is_a_miss_that_may_be_a_height_battle() {
+        let parent = block(90, 500, "pool1parent");
+        let outcome = slot_outcome(100, Some(&parent), &[block(104, 501, "pool1rival")]);
+
+        assert!(matches!(outcome, SlotOutcome::Missed { height_battle: true }));
+    }
+
+    #[test]
+    fn later_block_at_another_height_is_a_plain_miss() {
+        let parent = block(90, 500, "pool1parent");
+        let outcome = slot_outcome(100, Some(&parent), &[block(104, 502, "pool1rival")]);
+
+        assert!(matches!(outcome, SlotOutcome::Missed { height_battle: false }));
+    }
+
+    #[test]
+    fn no_parent_height_is_a_plain_miss() {
+        let outcome = slot_outcome(100, None, &[block(104, 501, "pool1rival")]);
+
+        assert!(matches!(outcome, SlotOutcome::Missed { height_battle: false }));
+    }
+
+    #[test]
+    fn empty_window_is_a_plain_miss() {
+        let parent = block(90, 500, "pool1parent");
+
+        assert!(matches!(slot_outcome(100, Some(&parent), &[]), SlotOutcome::Missed { height_battle: false }));
+    }
+}
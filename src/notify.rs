@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use log::{error, info, warn};
+
+use serde_json::json;
+
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+use crate::config::{DatabaseConfig, NotifierConfig, PoolConfig};
+use crate::error::BotError;
+use crate::event::Notification;
+use crate::monitor::BoxFuture;
+use crate::state::{Pending, StateStore};
+use crate::Matrix;
+
+/// Tries at delivering a message to one destination.
+const DELIVERY_ATTEMPTS: u32 = 3;
+/// Pause before a failed delivery is tried again, doubled each time.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Longest a webhook or Bot API request may take.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Somewhere rendered pool notifications are delivered.
+pub trait Notifier: Send + Sync {
+    /// Name used in log lines.
+    fn name(&self) -> &str;
+
+    /// Longest message, in characters, the service accepts. Batches are
+    /// split to fit.
+    fn max_length(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Delivers `text` for `pool`, retrying failures with `retry` on its
+    /// own. An error means the message was given up on.
+    fn send<'a>(&'a self, pool: &'a PoolConfig, text: &'a str) -> BoxFuture<'a, Result<(), BotError>>;
+}
+
+/// A notifier and the notifications it is sent.
+pub struct Route {
+    /// Name the outbox records deliveries under.
+    pub name: String,
+    pub notifier: Box<dyn Notifier>,
+    /// Empty for every pool.
+    pub pools: Vec<String>,
+    /// Empty for every kind.
+    pub events: Vec<String>,
+}
+
+impl Route {
+
+    fn wants(&self, notification: &Notification) -> bool {
+        (self.pools.is_empty() || self.pools.contains(&notification.ticker))
+            && (self.events.is_empty() || self.events.iter().any(|kind| kind == notification.event.kind()))
+    }
+
+}
+
+/// One route per configured notifier. Every event goes to the pool rooms on
+/// Matrix unless a `matrix` notifier says otherwise.
+pub fn routes(config: &DatabaseConfig, matrix: Arc<Matrix>) -> Result<Vec<Route>, BotError> {
+
+    let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+
+    let mut routes = vec![];
+
+    if !config.notifiers.iter().any(|notifier| notifier.service == "matrix") {
+        routes.push(Route { name: "matrix".to_owned(), notifier: Box::new(MatrixNotifier(matrix.clone())), pools: vec![], events: vec![] });
+    }
+
+    for (i, settings) in config.notifiers.iter().enumerate() {
+        let notifier: Box<dyn Notifier> = match settings.service.as_str() {
+            "matrix" => Box::new(MatrixNotifier(matrix.clone())),
+            "discord" => Box::new(DiscordNotifier { client: client.clone(), webhook: settings.webhook.clone() }),
+            "slack" => Box::new(SlackNotifier { client: client.clone(), webhook: settings.webhook.clone() }),
+            "telegram" => Box::new(TelegramNotifier::new(client.clone(), settings)),
+            other => unreachable!("notifier service `{}` passed validation but has no backend", other),
+        };
+
+        let name = format!("notifiers[{}] {}", i, settings.service);
+
+        routes.push(Route { name, notifier, pools: settings.pools.clone(), events: settings.events.clone() });
+    }
+
+    Ok(routes)
+}
+
+/// Posts to the pool's room and every room that joined by invitation.
+pub struct MatrixNotifier(pub Arc<Matrix>);
+
+impl Notifier for MatrixNotifier {
+
+    fn name(&self) -> &str {
+        "matrix"
+    }
+
+    fn send<'a>(&'a self, pool: &'a PoolConfig, text: &'a str) -> BoxFuture<'a, Result<(), BotError>> {
+        Box::pin(self.0.announce(pool, text))
+    }
+
+}
+
+/// Posts to a Discord channel webhook.
+pub struct DiscordNotifier {
+    client: reqwest::Client,
+    webhook: String,
+}
+
+impl Notifier for DiscordNotifier {
+
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    fn max_length(&self) -> usize {
+        2000
+    }
+
+    fn send<'a>(&'a self, _: &'a PoolConfig, text: &'a str) -> BoxFuture<'a, Result<(), BotError>> {
+        Box::pin(async move {
+            // Pool names and addresses should never ping anyone.
+            let body = json!({ "content": text, "allowed_mentions": { "parse": [] } });
+
+            retry("Discord webhook", || async {
+                self.client.post(&self.webhook).json(&body).send().await?.error_for_status()?;
+                Ok(())
+            }).await
+        })
+    }
+
+}
+
+/// Sends to a Telegram chat through the Bot API.
+pub struct TelegramNotifier {
+    client: reqwest::Client,
+    url: String,
+    chatid: String,
+}
+
+impl TelegramNotifier {
+
+    fn new(client: reqwest::Client, settings: &NotifierConfig) -> Self {
+        Self {
+            client,
+            url: format!("{}/bot{}/sendMessage", settings.apiurl.trim_end_matches('/'), settings.bottoken),
+            chatid: settings.chatid.clone(),
+        }
+    }
+
+}
+
+impl Notifier for TelegramNotifier {
+
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    fn max_length(&self) -> usize {
+        4096
+    }
+
+    fn send<'a>(&'a self, _: &'a PoolConfig, text: &'a str) -> BoxFuture<'a, Result<(), BotError>> {
+        Box::pin(async move {
+            let body = json!({ "chat_id": self.chatid, "text": text, "disable_web_page_preview": true });
+
+            retry("Telegram chat", || async {
+                self.client.post(&self.url).json(&body).send().await?.error_for_status()?;
+                Ok(())
+            }).await
+        })
+    }
+
+}
+
+/// Posts to a Slack incoming webhook.
+pub struct SlackNotifier {
+    client: reqwest::Client,
+    webhook: String,
+}
+
+impl Notifier for SlackNotifier {
+
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    fn send<'a>(&'a self, _: &'a PoolConfig, text: &'a str) -> BoxFuture<'a, Result<(), BotError>> {
+        Box::pin(async move {
+            let body = json!({ "text": slack_escape(text) });
+
+            retry("Slack webhook", || async {
+                self.client.post(&self.webhook).json(&body).send().await?.error_for_status()?;
+                Ok(())
+            }).await
+        })
+    }
+
+}
+
+/// Escapes the characters Slack reads as markup.
+fn slack_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The queue monitors hand committed notifications to, and its receiving
+/// end for `deliver`. It is unbounded so a slow route never holds up a
+/// monitor; what is in it is also in the outbox.
+pub fn channel() -> (mpsc::UnboundedSender<Pending>, mpsc::UnboundedReceiver<Pending>) {
+    mpsc::unbounded_channel()
+}
+
+/// Delivers the outbox left by the last run, then takes notifications off
+/// the queue until every sender is gone: logs them, drops muted kinds and
+/// hands each to the routes that want it. Every route runs in a task of its
+/// own, so one that is slow or down doesn't hold up the others. A
+/// notification leaves the outbox once every route it went to is done with
+/// it.
+pub async fn deliver(config: Arc<DatabaseConfig>, state: Arc<StateStore>, routes: Vec<Route>, mut queue: mpsc::UnboundedReceiver<Pending>) {
+
+    let (done, mut finished) = mpsc::unbounded_channel();
+    let mut senders = vec![];
+
+    for route in routes {
+        let route = Arc::new(route);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_route(config.clone(), route.clone(), receiver, done.clone()));
+        senders.push((route, sender));
+    }
+    drop(done);
+
+    // Routes still to finish each notification, by id.
+    let mut remaining: HashMap<u64, usize> = HashMap::new();
+
+    for pending in state.outbox() {
+        dispatch(&config, &state, &senders, &mut remaining, pending);
+    }
+
+    loop {
+        tokio::select! {
+            pending = queue.recv() => match pending {
+                Some(pending) => dispatch(&config, &state, &senders, &mut remaining, pending),
+                None => break,
+            },
+            Some((id, route)) = finished.recv() => {
+                let left = remaining.entry(id).or_insert(1);
+                *left -= 1;
+
+                let result = if *left == 0 {
+                    remaining.remove(&id);
+                    state.remove_pending(id)
+                } else {
+                    state.mark_delivered(id, &route)
+                };
+                if let Err(e) = result {
+                    error!("Unable to update the notification outbox: {:#}", e);
+                }
+            }
+        }
+    }
+
+    info!("Notification queue closed");
+}
+
+/// Hands `pending` to every route that wants it and hasn't delivered it yet.
+/// Reminders whose slot passed while they waited, in the outbox over a
+/// restart for instance, are dropped.
+fn dispatch(config: &DatabaseConfig, state: &StateStore, senders: &[(Arc<Route>, mpsc::UnboundedSender<Pending>)], remaining: &mut HashMap<u64, usize>, pending: Pending) {
+
+    let notification = &pending.notification;
+    let muted = config.mutedevents.iter().any(|kind| kind == notification.event.kind());
+    let expired = notification.event.expired(Utc::now());
+
+    let note = if muted { " (muted)" } else if expired { " (expired)" } else { "" };
+    info!("Event - {} {}{}: {:?}", notification.ticker, notification.event.kind(), note, notification.event);
+
+    let targets: Vec<&mpsc::UnboundedSender<Pending>> = senders.iter()
+        .filter(|(route, _)| !muted && !expired && route.wants(notification) && !pending.delivered.contains(&route.name))
+        .map(|(_, sender)| sender)
+        .collect();
+
+    if targets.is_empty() {
+        if let Err(e) = state.remove_pending(pending.id) {
+            error!("Unable to update the notification outbox: {:#}", e);
+        }
+        return;
+    }
+
+    remaining.insert(pending.id, targets.len());
+
+    for sender in targets {
+        let _ = sender.send(pending.clone());
+    }
+}
+
+/// Collects what reaches `route` for `notifybatch` seconds and sends it one
+/// message per pool, then reports every notification in the batch as done,
+/// delivered, expired while batched or given up on.
+async fn run_route(config: Arc<DatabaseConfig>, route: Arc<Route>, mut queue: mpsc::UnboundedReceiver<Pending>, done: mpsc::UnboundedSender<(u64, String)>) {
+
+    while let Some(first) = queue.recv().await {
+
+        let mut batch = vec![first];
+
+        if config.notifybatch > 0 {
+            let deadline = Instant::now() + Duration::from_secs(config.notifybatch);
+
+            while let Ok(Some(pending)) = time::timeout_at(deadline, queue.recv()).await {
+                batch.push(pending);
+            }
+        }
+
+        let now = Utc::now();
+
+        for pool in &config.pools {
+            let messages: Vec<String> = batch.iter()
+                .filter(|pending| pending.notification.ticker == pool.ticker && !pending.notification.event.expired(now))
+                .map(|pending| pending.notification.render())
+                .collect();
+
+            for text in join(&messages, route.notifier.max_length()) {
+                if let Err(e) = route.notifier.send(pool, &text).await {
+                    error!("Dropping {} notification for {} after {} attempts: {}", pool.ticker, route.notifier.name(), DELIVERY_ATTEMPTS, e);
+                }
+            }
+        }
+
+        for pending in batch {
+            let _ = done.send((pending.id, route.name.clone()));
+        }
+    }
+}
+
+/// Runs `attempt` until it succeeds, up to `DELIVERY_ATTEMPTS` times with a
+/// growing pause in between, and returns the last error if it never does.
+/// `target` names what is being sent to in log lines.
+pub async fn retry<F, Fut>(target: &str, mut attempt: F) -> Result<(), BotError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), BotError>>,
+{
+    let mut delay = RETRY_DELAY;
+    let mut tries = 1;
+
+    loop {
+        match attempt().await {
+            Ok(()) => return Ok(()),
+            Err(e) if tries < DELIVERY_ATTEMPTS => {
+                warn!("Unable to send to {}, retrying in {:?}: {}", target, delay, e);
+                time::sleep(delay).await;
+                delay *= 2;
+                tries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Joins `messages` into as few texts of at most `max_length` characters as
+/// possible. A message too long on its own is left for the service to reject.
+fn join(messages: &[String], max_length: usize) -> Vec<String> {
+
+    let mut texts: Vec<String> = vec![];
+
+    for message in messages {
+        match texts.last_mut() {
+            Some(text) if text.chars().count() + 2 + message.chars().count() <= max_length => {
+                text.push_str("\n\n");
+                text.push_str(message);
+            }
+            _ => texts.push(message.clone()),
+        }
+    }
+
+    texts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal::Decimal;
+
+    use serde_json::Value;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    use crate::event::Event;
+
+    struct Nowhere;
+
+    impl Notifier for Nowhere {
+
+        fn name(&self) -> &str {
+            "nowhere"
+        }
+
+        fn send<'a>(&'a self, _: &'a PoolConfig, _: &'a str) -> BoxFuture<'a, Result<(), BotError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+    }
+
+    fn route(pools: &[&str], events: &[&str]) -> Route {
+        Route {
+            name: "test".to_owned(),
+            notifier: Box::new(Nowhere),
+            pools: pools.iter().map(|pool| pool.to_string()).collect(),
+            events: events.iter().map(|kind| kind.to_string()).collect(),
+        }
+    }
+
+    fn notification(ticker: &str, event: Event) -> Notification {
+        Notification { ticker: ticker.to_owned(), event }
+    }
+
+    /// Answers one HTTP request on a local port with `200 OK` and hands back
+    /// its path and JSON body.
+    async fn stand_in() -> (String, JoinHandle<(String, Value)>) {
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 4096];
+
+            let (head, body_start, length) = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                received.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&received).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end].lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    break (text[..end].to_owned(), end + 4, length);
+                }
+            };
+
+            while received.len() < body_start + length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                received.extend_from_slice(&buffer[..read]);
+            }
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await.unwrap();
+
+            let path = head.split_whitespace().nth(1).unwrap().to_owned();
+            (path, serde_json::from_slice(&received[body_start..body_start + length]).unwrap())
+        });
+
+        (url, request)
+    }
+
+    #[test]
+    fn route_wants_configured_pools_and_kinds() {
+        let forged = notification("BALNC", Event::BlockForged { epoch: 400, forged: 1, assigned: None });
+        let stake = notification("OTHER", Event::StakeChanged { change: Decimal::new(5, 0) });
+
+        assert!(route(&[], &[]).wants(&forged));
+        assert!(route(&[], &[]).wants(&stake));
+        assert!(route(&["BALNC"], &[]).wants(&forged));
+        assert!(!route(&["BALNC"], &[]).wants(&stake));
+        assert!(route(&[], &["stake_changed"]).wants(&stake));
+        assert!(!route(&[], &["stake_changed"]).wants(&forged));
+        assert!(!route(&["BALNC"], &["stake_changed"]).wants(&stake));
+    }
+
+    #[test]
+    fn join_packs_messages_up_to_max_length() {
+        let messages = vec!["a".repeat(4), "b".repeat(4), "c".repeat(4)];
+
+        assert_eq!(join(&messages, usize::MAX), vec!["aaaa\n\nbbbb\n\ncccc"]);
+        assert_eq!(join(&messages, 10), vec!["aaaa\n\nbbbb", "cccc"]);
+        assert_eq!(join(&messages, 9), vec!["aaaa", "bbbb", "cccc"]);
+        assert_eq!(join(&["x".repeat(20)], 10), vec!["x".repeat(20)]);
+        assert!(join(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn join_counts_characters_not_bytes() {
+        let messages = vec!["₳₳".to_owned(), "₳₳".to_owned()];
+
+        assert_eq!(join(&messages, 6), vec!["₳₳\n\n₳₳"]);
+    }
+
+    #[test]
+    fn slack_markup_is_escaped() {
+        assert_eq!(slack_escape("<@here> & <#general>"), "&lt;@here&gt; &amp; &lt;#general&gt;");
+    }
+
+    #[tokio::test]
+    async fn discord_posts_content_without_mentions() {
+        let (url, request) = stand_in().await;
+        let notifier = DiscordNotifier { client: reqwest::Client::new(), webhook: format!("{}/api/webhooks/1/token", url) };
+
+        notifier.send(&PoolConfig::default(), "⚒️   BALNC   hello @everyone").await.unwrap();
+
+        let (path, body) = request.await.unwrap();
+        assert_eq!(path, "/api/webhooks/1/token");
+        assert_eq!(body["content"], "⚒️   BALNC   hello @everyone");
+        assert_eq!(body["allowed_mentions"]["parse"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn telegram_sends_to_the_chat_through_the_api_url() {
+        let (url, request) = stand_in().await;
+        let settings = NotifierConfig { apiurl: format!("{}/", url), bottoken: "123:abc".to_owned(), chatid: "-10042".to_owned(), ..NotifierConfig::default() };
+        let notifier = TelegramNotifier::new(reqwest::Client::new(), &settings);
+
+        notifier.send(&PoolConfig::default(), "hello").await.unwrap();
+
+        let (path, body) = request.await.unwrap();
+        assert_eq!(path, "/bot123:abc/sendMessage");
+        assert_eq!(body["chat_id"], "-10042");
+        assert_eq!(body["text"], "hello");
+        assert_eq!(body["disable_web_page_preview"], true);
+    }
+
+    #[tokio::test]
+    async fn slack_posts_escaped_text() {
+        let (url, request) = stand_in().await;
+        let notifier = SlackNotifier { client: reqwest::Client::new(), webhook: format!("{}/services/T/B/X", url) };
+
+        notifier.send(&PoolConfig::default(), "a <b> & c").await.unwrap();
+
+        let (path, body) = request.await.unwrap();
+        assert_eq!(path, "/services/T/B/X");
+        assert_eq!(body, json!({ "text": "a &lt;b&gt; &amp; c" }));
+    }
+}
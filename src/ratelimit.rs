@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::DatabaseConfig;
+
+/// Result of asking a limiter for a token.
+pub enum Throttle {
+    Allowed,
+    /// Out of tokens. `first` is set only for the first refusal since the
+    /// key last had a token, so a throttled sender is told once, not on
+    /// every message.
+    Limited { retry_after: Duration, first: bool },
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    notified: bool,
+}
+
+/// Token buckets keyed by sender or room. Each bucket holds up to `burst`
+/// tokens and refills at `per_minute` tokens a minute.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        Self {
+            burst: f64::from(burst),
+            per_second: f64::from(per_minute) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn acquire(&self, key: &str) -> Throttle {
+        self.acquire_at(key, Instant::now())
+    }
+
+    fn acquire_at(&self, key: &str, now: Instant) -> Throttle {
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        buckets.retain(|_, bucket| !self.idle(bucket, now));
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket { tokens: self.burst, last: now, notified: false });
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.notified = false;
+            return Throttle::Allowed;
+        }
+
+        let retry_after = if self.per_second > 0.0 {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+        } else {
+            Duration::MAX
+        };
+
+        let first = !bucket.notified;
+        bucket.notified = true;
+
+        Throttle::Limited { retry_after, first }
+    }
+
+    /// Whether `bucket` has been full for longer than a full refill takes, so
+    /// forgetting it changes nothing and senders seen once don't pile up.
+    fn idle(&self, bucket: &Bucket, now: Instant) -> bool {
+
+        if self.per_second <= 0.0 {
+            return false;
+        }
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        let refill = self.burst / self.per_second;
+
+        elapsed - (self.burst - bucket.tokens) / self.per_second > refill
+    }
+
+}
+
+/// Replies to read-only commands, reused until they are `ttl` old.
+pub struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, String)>>,
+}
+
+impl ResponseCache {
+
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+
+        entries.retain(|_, (created, _)| created.elapsed() < ttl);
+        entries.get(key).map(|(_, reply)| reply.clone())
+    }
+
+    pub fn put(&self, key: String, reply: String) {
+        if !self.ttl.is_zero() {
+            self.entries.lock().unwrap().insert(key, (Instant::now(), reply));
+        }
+    }
+
+}
+
+/// Limits applied to chat commands before they run.
+pub struct CommandLimits {
+    pub senders: RateLimiter,
+    pub rooms: RateLimiter,
+    pub cache: ResponseCache,
+}
+
+impl CommandLimits {
+
+    pub fn new(config: &DatabaseConfig) -> Self {
+        Self {
+            senders: RateLimiter::new(config.usercommandburst, config.usercommandrate),
+            rooms: RateLimiter::new(config.roomcommandburst, config.roomcommandrate),
+            cache: ResponseCache::new(Duration::from_secs(config.commandcachettl.unwrap_or_default())),
+        }
+    }
+
+    /// Takes a token from both the sender's and the room's bucket. The room
+    /// is only charged when the sender is within their own limit.
+    pub fn acquire(&self, sender: &str, room: &str) -> Throttle {
+        match self.senders.acquire(sender) {
+            Throttle::Allowed => self.rooms.acquire(room),
+            limited => limited,
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(throttle: Throttle) -> Option<(Duration, bool)> {
+        match throttle {
+            Throttle::Allowed => None,
+            Throttle::Limited { retry_after, first } => Some((retry_after, first)),
+        }
+    }
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let limiter = RateLimiter::new(2, 6);
+        let start = Instant::now();
+
+        assert!(limited(limiter.acquire_at("@alice", start)).is_none());
+        assert!(limited(limiter.acquire_at("@alice", start)).is_none());
+        assert_eq!(limited(limiter.acquire_at("@alice", start)), Some((Duration::from_secs(10), true)));
+
+        // A token every ten seconds, and no more than the burst.
+        assert!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(10))).is_none());
+        assert!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(10))).is_some());
+        assert!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(600))).is_none());
+        assert!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(600))).is_none());
+        assert!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(600))).is_some());
+    }
+
+    #[test]
+    fn tells_a_throttled_sender_once() {
+        let limiter = RateLimiter::new(1, 6);
+        let start = Instant::now();
+
+        assert!(limited(limiter.acquire_at("@alice", start)).is_none());
+        assert_eq!(limited(limiter.acquire_at("@alice", start)).map(|(_, first)| first), Some(true));
+        assert_eq!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(1))).map(|(_, first)| first), Some(false));
+        assert!(limited(limiter.acquire_at("@bob", start)).is_none());
+
+        assert!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(10))).is_none());
+        assert_eq!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(10))).map(|(_, first)| first), Some(true));
+    }
+
+    #[test]
+    fn never_refills_without_a_rate() {
+        let limiter = RateLimiter::new(1, 0);
+        let start = Instant::now();
+
+        assert!(limited(limiter.acquire_at("@alice", start)).is_none());
+        assert_eq!(limited(limiter.acquire_at("@alice", start + Duration::from_secs(86_400))), Some((Duration::MAX, true)));
+    }
+
+    #[test]
+    fn forgets_buckets_full_for_a_whole_refill() {
+        let limiter = RateLimiter::new(2, 6);
+        let start = Instant::now();
+
+        limiter.acquire_at("@alice", start);
+        limiter.acquire_at("@bob", start + Duration::from_secs(30));
+
+        // Alice is full again 10s in and stays so past 30s; Bob was just seen.
+        limiter.acquire_at("@carol", start + Duration::from_secs(41));
+
+        let buckets = limiter.buckets.lock().unwrap();
+        let mut keys: Vec<&str> = buckets.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["@bob", "@carol"]);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use log::{error, info};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use crate::epoch::Genesis;
+use crate::state::write_json;
+
+/// Leader schedule of one pool for one epoch.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EpochSchedule {
+    /// Slots the pool is leader in.
+    pub assigned: i64,
+    /// The leader slots, in order.
+    pub slots: Vec<LeaderSlot>,
+}
+
+/// A slot the pool may forge a block in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaderSlot {
+    /// Absolute slot number.
+    pub slot: u64,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Schedules {
+    /// Schedules keyed by ticker, then epoch.
+    pools: HashMap<String, BTreeMap<i64, EpochSchedule>>,
+}
+
+/// cncli `leaderlog` output.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Leaderlog {
+    epoch: i64,
+    assigned_slots: Vec<LeaderlogSlot>,
+}
+
+#[derive(Deserialize)]
+struct LeaderlogSlot {
+    slot: u64,
+    at: DateTime<Utc>,
+}
+
+/// One entry of `cardano-cli query leadership-schedule --output-json`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliSlot {
+    slot_number: u64,
+    slot_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScheduleFile {
+    Leaderlog(Leaderlog),
+    Cli(Vec<CliSlot>),
+}
+
+/// Reads a cncli `leaderlog`, or a `cardano-cli` leadership schedule as JSON
+/// or as its default table. The cardano-cli output doesn't name its epoch, so
+/// it is worked out from the slots, or taken from `epoch` when the schedule is
+/// empty.
+pub fn parse(contents: &str, genesis: &Genesis, epoch: Option<i64>) -> anyhow::Result<(i64, EpochSchedule)> {
+
+    let (fileepoch, mut slots) = match serde_json::from_str::<ScheduleFile>(contents) {
+        Ok(ScheduleFile::Leaderlog(log)) => {
+            let slots = log.assigned_slots.into_iter().map(|slot| LeaderSlot { slot: slot.slot, at: slot.at }).collect();
+            (Some(log.epoch), slots)
+        }
+        Ok(ScheduleFile::Cli(slots)) => {
+            let slots: Vec<LeaderSlot> = slots.into_iter().map(|slot| LeaderSlot { slot: slot.slot_number, at: slot.slot_time }).collect();
+            (slot_epoch(&slots, genesis)?, slots)
+        }
+        Err(e) => match parse_table(contents) {
+            Some(slots) => (slot_epoch(&slots, genesis)?, slots),
+            None => return Err(anyhow!(e).context("Not a cncli leaderlog or cardano-cli leadership schedule")),
+        },
+    };
+
+    slots.sort_by_key(|slot| slot.slot);
+
+    let epoch = match (fileepoch, epoch) {
+        (Some(fileepoch), Some(epoch)) if fileepoch != epoch => bail!("The schedule is for epoch {}, not {}", fileepoch, epoch),
+        (Some(epoch), _) | (None, Some(epoch)) => epoch,
+        (None, None) => bail!("The schedule is empty, so its epoch must be given"),
+    };
+
+    Ok((epoch, EpochSchedule { assigned: slots.len() as i64, slots }))
+}
+
+/// The one epoch every slot falls in, or `None` for no slots.
+fn slot_epoch(slots: &[LeaderSlot], genesis: &Genesis) -> anyhow::Result<Option<i64>> {
+
+    let mut epochs: Vec<i64> = slots.iter().map(|slot| genesis.epoch_of_slot(slot.slot) as i64).collect();
+    epochs.sort_unstable();
+    epochs.dedup();
+
+    if epochs.len() > 1 {
+        bail!("The schedule covers more than one epoch: {:?}", epochs);
+    }
+
+    Ok(epochs.first().copied())
+}
+
+/// Reads the `SlotNo  UTC Time` table cardano-cli prints without
+/// `--output-json`. Header and separator lines are skipped; any other line
+/// that isn't a slot makes the whole text unreadable.
+fn parse_table(contents: &str) -> Option<Vec<LeaderSlot>> {
+
+    let mut slots = vec![];
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with("SlotNo") || line.chars().all(|c| c == '-') {
+            continue;
+        }
+
+        let (slot, time) = line.split_once(char::is_whitespace)?;
+        let time = time.trim().strip_suffix("UTC")?.trim();
+
+        slots.push(LeaderSlot {
+            slot: slot.parse().ok()?,
+            at: NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f").ok()?.and_utc(),
+        });
+    }
+
+    Some(slots)
+}
+
+/// Leader schedules kept in a JSON file of their own. Schedules are imported
+/// by a separate `import-schedule` run, so the file is read again whenever it
+/// changes on disk.
+pub struct ScheduleStore {
+    path: PathBuf,
+    state: Mutex<(Option<SystemTime>, Schedules)>,
+}
+
+impl ScheduleStore {
+
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+
+        let store = Self { path: PathBuf::from(path), state: Mutex::new((None, Schedules::default())) };
+        store.refresh()?;
+
+        Ok(store)
+    }
+
+    /// Assigned slot count of `ticker` in `epoch`, if its schedule was
+    /// imported.
+    pub fn assigned(&self, ticker: &str, epoch: i64) -> Option<i64> {
+
+        if let Err(e) = self.refresh() {
+            error!("Unable to reload leader schedules: {:#}", e);
+        }
+
+        let state = self.state.lock().unwrap();
+        state.1.pools.get(ticker)?.get(&epoch).map(|schedule| schedule.assigned)
+    }
+
+    /// Leader slots of `ticker` due after `from` and no later than `until`,
+    /// in order.
+    pub fn slots_between(&self, ticker: &str, from: DateTime<Utc>, until: DateTime<Utc>) -> Vec<LeaderSlot> {
+
+        if let Err(e) = self.refresh() {
+            error!("Unable to reload leader schedules: {:#}", e);
+        }
+
+        let state = self.state.lock().unwrap();
+        let Some(epochs) = state.1.pools.get(ticker) else { return vec![] };
+
+        epochs.values()
+            .flat_map(|schedule| schedule.slots.iter())
+            .filter(|slot| slot.at > from && slot.at <= until)
+            .cloned()
+            .collect()
+    }
+
+    pub fn import(&self, ticker: &str, epoch: i64, schedule: EpochSchedule) -> anyhow::Result<()> {
+
+        self.refresh()?;
+
+        let mut state = self.state.lock().unwrap();
+        state.1.pools.entry(ticker.to_owned()).or_default().insert(epoch, schedule);
+
+        write_json(&self.path, &state.1, "schedule")?;
+        state.0 = fs::metadata(&self.path)?.modified().ok();
+
+        Ok(())
+    }
+
+    /// Reads the file again if it changed since it was last read. A missing
+    /// file holds no schedules.
+    fn refresh(&self) -> anyhow::Result<()> {
+
+        let modified = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        if modified.is_some() && state.0 == modified {
+            return Ok(());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Unable to read schedule file {}", self.path.display()))?;
+
+        state.1 = serde_json::from_str(&contents)
+            .with_context(|| format!("Unable to parse schedule file {}", self.path.display()))?;
+        state.0 = modified;
+
+        info!("Loaded leader schedules from {}", self.path.display());
+
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First slot of mainnet epoch 500.
+    const EPOCH_500: u64 = 4_492_800 + 292 * 432_000;
+
+    fn mainnet() -> Genesis {
+        Genesis::network("mainnet").unwrap()
+    }
+
+    #[test]
+    fn reads_cncli_leaderlog() {
+        let contents = format!(r#"{{
+            "status": "ok", "epoch": 500, "epochNonce": "abc", "epochSlots": 2,
+            "assignedSlots": [
+                {{ "no": 2, "slot": {}, "slotInEpoch": 200, "at": "2024-07-01T12:03:20+00:00" }},
+                {{ "no": 1, "slot": {}, "slotInEpoch": 100, "at": "2024-07-01T12:01:40+00:00" }}
+            ]
+        }}"#, EPOCH_500 + 200, EPOCH_500 + 100);
+
+        let (epoch, schedule) = parse(&contents, &mainnet(), None).unwrap();
+
+        assert_eq!(epoch, 500);
+        assert_eq!(schedule.assigned, 2);
+        assert_eq!(schedule.slots.iter().map(|slot| slot.slot).collect::<Vec<_>>(), vec![EPOCH_500 + 100, EPOCH_500 + 200]);
+    }
+
+    #[test]
+    fn leaderlog_epoch_must_match_the_given_one() {
+        let contents = r#"{ "epoch": 500, "assignedSlots": [] }"#;
+
+        assert_eq!(parse(contents, &mainnet(), Some(500)).unwrap().0, 500);
+        assert!(parse(contents, &mainnet(), Some(501)).is_err());
+    }
+
+    #[test]
+    fn reads_cardano_cli_json_and_works_out_the_epoch() {
+        let contents = format!(r#"[
+            {{ "slotNumber": {}, "slotTime": "2024-07-01T12:01:40Z" }}
+        ]"#, EPOCH_500 + 100);
+
+        let (epoch, schedule) = parse(&contents, &mainnet(), None).unwrap();
+
+        assert_eq!(epoch, 500);
+        assert_eq!(schedule.slots, vec![LeaderSlot { slot: EPOCH_500 + 100, at: "2024-07-01T12:01:40Z".parse().unwrap() }]);
+    }
+
+    #[test]
+    fn reads_cardano_cli_table() {
+        let contents = format!("     SlotNo                          UTC Time\n-------------------------------------------------------------\n     {}                   2024-07-01 12:01:40 UTC\n     {}                   2024-07-01 12:03:20.5 UTC\n",
+            EPOCH_500 + 100, EPOCH_500 + 200);
+
+        let (epoch, schedule) = parse(&contents, &mainnet(), None).unwrap();
+
+        assert_eq!(epoch, 500);
+        assert_eq!(schedule.assigned, 2);
+        assert_eq!(schedule.slots[1].at, "2024-07-01T12:03:20.500Z".parse::<DateTime<Utc>>().unwrap());
+    }
+
+    #[test]
+    fn empty_schedule_needs_an_epoch() {
+        assert!(parse("[]", &mainnet(), None).is_err());
+
+        let (epoch, schedule) = parse("[]", &mainnet(), Some(500)).unwrap();
+        assert_eq!((epoch, schedule.assigned), (500, 0));
+    }
+
+    #[test]
+    fn rejects_schedules_across_epochs() {
+        let contents = format!(r#"[
+            {{ "slotNumber": {}, "slotTime": "2024-07-01T12:01:40Z" }},
+            {{ "slotNumber": {}, "slotTime": "2024-07-06T12:01:40Z" }}
+        ]"#, EPOCH_500 + 100, EPOCH_500 + 432_100);
+
+        assert!(parse(&contents, &mainnet(), None).is_err());
+    }
+
+    #[test]
+    fn rejects_other_text() {
+        assert!(parse("not a schedule", &mainnet(), None).is_err());
+        assert!(parse_table("12345   yesterday").is_none());
+        assert!(parse_table("12345   2024-07-01 12:01:40").is_none());
+        assert_eq!(parse_table("SlotNo  UTC Time\n-----\n"), Some(vec![]));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::event::Notification;
+
+/// A room the bot joined by invitation, and the pools announced there.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct JoinedRoom {
+    pub room_id: String,
+    pub inviter: String,
+    pub pools: Vec<String>,
+}
+
+/// A notification that is not yet delivered everywhere it should go.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pending {
+    pub id: u64,
+    pub notification: Notification,
+    /// Routes that already delivered it.
+    #[serde(default)]
+    pub delivered: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct BotState {
+    /// Monitor snapshots keyed by `<ticker>/<monitor>`.
+    snapshots: HashMap<String, serde_json::Value>,
+    rooms: Vec<JoinedRoom>,
+    /// Notifications queued by monitors and not yet delivered.
+    outbox: Vec<Pending>,
+    nextid: u64,
+}
+
+/// Keeps the monitor snapshots, undelivered notifications and joined rooms
+/// in a JSON file so changes that happen while the bot is down, or that it
+/// had no time to deliver, are announced on the next start instead of being
+/// lost. Shared by the scheduler, the notification pipeline and the Matrix
+/// handlers.
+pub struct StateStore {
+    path: PathBuf,
+    state: Mutex<BotState>,
+}
+
+impl StateStore {
+
+    /// Reads the saved state. A missing file is a first start and yields an
+    /// empty state.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+
+        let path = PathBuf::from(path);
+
+        let state = if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Unable to read state file {}", path.display()))?;
+
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Unable to parse state file {}", path.display()))?
+        } else {
+            BotState::default()
+        };
+
+        Ok(Self { path, state: Mutex::new(state) })
+    }
+
+    /// The snapshot saved under `key`, if there is one.
+    pub fn snapshot<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+
+        let state = self.state.lock().unwrap();
+
+        state.snapshots.get(key)
+            .map(|value| T::deserialize(value).with_context(|| format!("Unable to parse snapshot {}", key)))
+            .transpose()
+    }
+
+    /// Saves `snapshot` under `key` together with the notifications it
+    /// produced, in one write, so a stop before they are delivered can't lose
+    /// them. Returns the notifications as they were put in the outbox.
+    pub fn commit<T: Serialize>(&self, key: &str, snapshot: &T, notifications: Vec<Notification>) -> anyhow::Result<Vec<Pending>> {
+
+        let mut state = self.state.lock().unwrap();
+        state.snapshots.insert(key.to_owned(), serde_json::to_value(snapshot)?);
+
+        let mut pending = Vec::with_capacity(notifications.len());
+
+        for notification in notifications {
+            pending.push(Pending { id: state.nextid, notification, delivered: vec![] });
+            state.nextid += 1;
+        }
+        state.outbox.extend(pending.iter().cloned());
+
+        self.persist(&state)?;
+
+        Ok(pending)
+    }
+
+    /// Notifications left undelivered, oldest first.
+    pub fn outbox(&self) -> Vec<Pending> {
+        self.state.lock().unwrap().outbox.clone()
+    }
+
+    /// Records that `route` is done with notification `id`.
+    pub fn mark_delivered(&self, id: u64, route: &str) -> anyhow::Result<()> {
+
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(pending) = state.outbox.iter_mut().find(|pending| pending.id == id) {
+            pending.delivered.push(route.to_owned());
+        }
+
+        self.persist(&state)
+    }
+
+    /// Drops notification `id` once every route is done with it.
+    pub fn remove_pending(&self, id: u64) -> anyhow::Result<()> {
+
+        let mut state = self.state.lock().unwrap();
+        state.outbox.retain(|pending| pending.id != id);
+
+        self.persist(&state)
+    }
+
+    /// Rooms joined by invitation that receive alerts for `ticker`.
+    pub fn rooms_for(&self, ticker: &str) -> Vec<String> {
+        self.state.lock().unwrap().rooms.iter()
+            .filter(|room| room.pools.iter().any(|pool| pool == ticker))
+            .map(|room| room.room_id.clone())
+            .collect()
+    }
+
+    pub fn add_room(&self, room: JoinedRoom) -> anyhow::Result<()> {
+
+        let mut state = self.state.lock().unwrap();
+
+        state.rooms.retain(|existing| existing.room_id != room.room_id);
+        state.rooms.push(room);
+
+        self.persist(&state)
+    }
+
+    /// Forgets a room the bot left or was removed from. Returns whether it
+    /// was recorded.
+    pub fn remove_room(&self, room_id: &str) -> anyhow::Result<bool> {
+
+        let mut state = self.state.lock().unwrap();
+
+        let before = state.rooms.len();
+        state.rooms.retain(|room| room.room_id != room_id);
+
+        if state.rooms.len() == before {
+            return Ok(false);
+        }
+
+        self.persist(&state)?;
+
+        Ok(true)
+    }
+
+    fn persist(&self, state: &BotState) -> anyhow::Result<()> {
+        write_json(&self.path, state, "state")
+    }
+
+}
+
+/// Writes `value` as JSON to the `what` file at `path` through a temporary
+/// file next to it, so a crash mid-write never leaves a truncated file behind.
+pub fn write_json<T: Serialize>(path: &Path, value: &T, what: &str) -> anyhow::Result<()> {
+    replace_file(path, value, what, 0o666)
+}
+
+/// Like `write_json`, for files holding secrets: only the owner may read the
+/// result.
+pub fn write_private_json<T: Serialize>(path: &Path, value: &T, what: &str) -> anyhow::Result<()> {
+    replace_file(path, value, what, 0o600)
+}
+
+/// Writes the temporary file with `mode`, less the umask, and moves it over
+/// `path`. A temporary file left by a crash is removed first, so it can't
+/// pass on its permissions.
+fn replace_file<T: Serialize>(path: &Path, value: &T, what: &str, mode: u32) -> anyhow::Result<()> {
+
+    let contents = serde_json::to_string_pretty(value)?;
+    let tmp = path.with_extension("tmp");
+
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Unable to remove {} file {}", what, tmp.display()));
+        }
+        _ => {}
+    }
+
+    OpenOptions::new().write(true).create_new(true).mode(mode).open(&tmp)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("Unable to write {} file {}", what, tmp.display()))?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("Unable to replace {} file {}", what, path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn private_files_are_owner_only() {
+        let path = std::env::temp_dir().join(format!("balance_bot_state_test_{}.json", std::process::id()));
+
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private_json(&path, &vec!["token"], "test").unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(serde_json::from_str::<Vec<String>>(&contents).unwrap(), vec!["token"]);
+        assert!(!path.with_extension("tmp").exists());
+    }
+}